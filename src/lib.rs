@@ -0,0 +1,1980 @@
+//! Querying CloudWatch Logs with paging, retries and a local cache of results.
+
+#[macro_use]
+extern crate serde_derive;
+
+use std::collections::{BinaryHeap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use std::{cmp, env, fs, io, thread};
+
+use anyhow::Error;
+
+use chrono::{DateTime, Local, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use chrono_english::{parse_date_string, Dialect};
+use chrono_tz::Tz;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rusoto_core::request::HttpClientFuture;
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{DispatchSignedRequest, HttpClient, Region, RusotoError};
+use rusoto_credential::{AutoRefreshingProvider, ChainProvider, ProfileProvider};
+use rusoto_logs::{
+    CloudWatchLogs, CloudWatchLogsClient, DescribeLogGroupsRequest, DescribeLogStreamsError,
+    DescribeLogStreamsRequest, FilterLogEventsError, FilterLogEventsRequest,
+    GetQueryResultsRequest, StartQueryError, StartQueryRequest,
+};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+/// How often -v was given. Diagnostics of a higher level are not printed.
+pub static VERBOSITY: AtomicUsize = AtomicUsize::new(0);
+
+/// Prints a diagnostic to stderr when -v was given at least `level` times.
+#[macro_export]
+macro_rules! verbose {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::VERBOSITY.load(::std::sync::atomic::Ordering::Relaxed) >= $level {
+            eprintln!("debug: {}", format_args!($($arg)*));
+        }
+    };
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LogEvent {
+    /// Only set when querying multiple log groups.
+    #[serde(
+        rename = "logGroupName",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub log_group_name: Option<String>,
+    #[serde(rename = "eventId")]
+    pub event_id: Option<String>,
+    #[serde(rename = "ingestionTime")]
+    pub ingestion_time: Option<i64>,
+    #[serde(rename = "logStreamName")]
+    pub log_stream_name: Option<String>,
+    #[serde(rename = "message")]
+    pub message: Option<String>,
+    #[serde(rename = "timestamp")]
+    pub timestamp: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LogGroup {
+    #[serde(rename = "logGroupName")]
+    pub log_group_name: Option<String>,
+    #[serde(rename = "creationTime")]
+    pub creation_time: Option<i64>,
+    #[serde(rename = "retentionInDays")]
+    pub retention_in_days: Option<i64>,
+    #[serde(rename = "storedBytes")]
+    pub stored_bytes: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LogStream {
+    #[serde(rename = "logStreamName")]
+    pub log_stream_name: Option<String>,
+    #[serde(rename = "creationTime")]
+    pub creation_time: Option<i64>,
+    #[serde(rename = "firstEventTimestamp")]
+    pub first_event_timestamp: Option<i64>,
+    #[serde(rename = "lastEventTimestamp")]
+    pub last_event_timestamp: Option<i64>,
+    #[serde(rename = "storedBytes")]
+    pub stored_bytes: Option<i64>,
+}
+
+/// The time zone used to interpret and display times.
+#[derive(Clone, Copy)]
+pub enum Zone {
+    Local,
+    Named(Tz),
+}
+
+impl Zone {
+    /// Parses a time like `parse_time`, anchored in this zone.
+    pub fn parse(self, value: &str, now: DateTime<Utc>, dialect: Dialect) -> Result<i64, String> {
+        match self {
+            Zone::Local => parse_time(value, now.with_timezone(&Local), dialect),
+            Zone::Named(tz) => parse_time(value, now.with_timezone(&tz), dialect),
+        }
+    }
+
+    /// Formats a timestamp in millis, as RFC3339 with milliseconds when no format is given.
+    pub fn format(self, timestamp: i64, format: Option<&str>) -> String {
+        fn format_in<T: TimeZone>(tz: T, timestamp: i64, format: Option<&str>) -> String
+        where
+            T::Offset: fmt::Display,
+        {
+            let time = tz.timestamp_millis(timestamp);
+            match format {
+                Some(format) => time.format(format).to_string(),
+                None => time.to_rfc3339_opts(SecondsFormat::Millis, false),
+            }
+        }
+        match self {
+            Zone::Local => format_in(Local, timestamp, format),
+            Zone::Named(tz) => format_in(tz, timestamp, format),
+        }
+    }
+}
+
+pub const REGIONS: &[&str] = &[
+    "ap-east-1",
+    "ap-northeast-1",
+    "ap-northeast-2",
+    "ap-south-1",
+    "ap-southeast-1",
+    "ap-southeast-2",
+    "ca-central-1",
+    "eu-central-1",
+    "eu-west-1",
+    "eu-west-2",
+    "eu-west-3",
+    "eu-north-1",
+    "sa-east-1",
+    "us-east-1",
+    "us-east-2",
+    "us-west-1",
+    "us-west-2",
+    "us-gov-east-1",
+    "us-gov-west-1",
+    "cn-north-1",
+    "cn-northwest-1",
+];
+
+/// A log group given by its ARN, like
+/// `arn:aws:logs:us-east-1:123456789012:log-group:my-group:*`.
+#[derive(Debug, PartialEq)]
+pub struct LogGroupArn<'a> {
+    pub region: &'a str,
+    pub account: &'a str,
+    pub name: &'a str,
+}
+
+impl<'a> LogGroupArn<'a> {
+    /// Parses an ARN of a log group. Returns `None` when the value isn't an
+    /// ARN, so that it can be used as a name instead.
+    pub fn parse(value: &'a str) -> Result<Option<LogGroupArn<'a>>, CloudwatchError> {
+        if !value.starts_with("arn:") {
+            return Ok(None);
+        }
+        match value.split(':').collect::<Vec<_>>().as_slice() {
+            ["arn", _, "logs", region, account, "log-group", name]
+            | ["arn", _, "logs", region, account, "log-group", name, "*"]
+                if !name.is_empty() =>
+            {
+                Ok(Some(LogGroupArn {
+                    region,
+                    account,
+                    name,
+                }))
+            }
+            _ => Err(CloudwatchError::Args(format!(
+                "'{}' is not the ARN of a log group",
+                value
+            ))),
+        }
+    }
+}
+
+/// The ways a query can fail, each with a message for the user.
+#[derive(Debug)]
+pub enum CloudwatchError {
+    /// A request to AWS failed.
+    Aws(String),
+    /// The cache could not be read or written.
+    Cache(String, io::Error),
+    /// A time could not be parsed, with the input and the reason.
+    DateParse(String, String),
+    /// Reading input or writing output failed.
+    Io(String, io::Error),
+    /// The command line arguments are invalid.
+    Args(String),
+    /// There is no cached result while AWS may not be contacted.
+    NotCached,
+}
+
+impl fmt::Display for CloudwatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CloudwatchError::Aws(message) => f.write_str(message),
+            CloudwatchError::Cache(message, _) => f.write_str(message),
+            CloudwatchError::DateParse(value, reason) => write!(
+                f,
+                "could not parse time '{}': {}. Examples: '2h', 'yesterday 9am', '2024-01-01'.",
+                value, reason
+            ),
+            CloudwatchError::Io(message, _) => f.write_str(message),
+            CloudwatchError::Args(message) => f.write_str(message),
+            CloudwatchError::NotCached => f.write_str("no cached result for this query"),
+        }
+    }
+}
+
+impl std::error::Error for CloudwatchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CloudwatchError::Cache(_, e) | CloudwatchError::Io(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl CloudwatchError {
+    /// Returns a function for `map_err` that wraps a cache I/O error.
+    pub fn cache(message: &str) -> impl FnOnce(io::Error) -> CloudwatchError + '_ {
+        move |e| CloudwatchError::Cache(message.to_string(), e)
+    }
+
+    /// Returns a function for `map_err` that wraps any other I/O error.
+    pub fn io(message: &str) -> impl FnOnce(io::Error) -> CloudwatchError + '_ {
+        move |e| CloudwatchError::Io(message.to_string(), e)
+    }
+}
+
+/// A cache file being written. It is deleted when dropped unless it was
+/// persisted, so failed queries don't leave partial results behind.
+pub struct PartialFile {
+    path: PathBuf,
+    file: Option<CacheWriter>,
+}
+
+/// The contents of a cache file, gzip compressed unless disabled.
+enum CacheWriter {
+    Plain(File),
+    Gzip(GzEncoder<File>),
+}
+
+impl PartialFile {
+    pub fn create(path: PathBuf, compress: bool) -> io::Result<PartialFile> {
+        let mut file = File::create(&path)?;
+        file.write_all(CACHE_MAGIC)?;
+        file.write_all(&[CACHE_FORMAT])?;
+        Ok(PartialFile {
+            path,
+            file: Some(if compress {
+                CacheWriter::Gzip(GzEncoder::new(file, Compression::default()))
+            } else {
+                CacheWriter::Plain(file)
+            }),
+        })
+    }
+
+    /// Moves the completed file to `path`.
+    pub fn persist(mut self, path: &Path) -> io::Result<()> {
+        match self.file.take() {
+            Some(CacheWriter::Plain(mut file)) => file.flush()?,
+            Some(CacheWriter::Gzip(encoder)) => encoder.finish()?.flush()?,
+            None => {}
+        }
+        fs::rename(&self.path, path).inspect_err(|_| {
+            let _ = fs::remove_file(&self.path);
+        })
+    }
+}
+
+impl Write for PartialFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.file.as_mut().unwrap() {
+            CacheWriter::Plain(file) => file.write(buf),
+            CacheWriter::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut().unwrap() {
+            CacheWriter::Plain(file) => file.flush(),
+            CacheWriter::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Marks the start of a cache file, followed by the `CACHE_FORMAT` byte.
+const CACHE_MAGIC: &[u8] = b"CWLOG\0";
+
+/// The version of the cache file contents, to be bumped on changes that older
+/// versions can't read.
+const CACHE_FORMAT: u8 = 1;
+
+/// The version of the cache keys, hashed into every key. Bumping it discards
+/// every cache entry, so it should only change when equal keys no longer mean
+/// equal results, e.g. when `cache_key` starts hashing its inputs differently.
+/// Versions of the file contents are handled by `CACHE_FORMAT` instead.
+const CACHE_KEY_VERSION: u8 = 5;
+
+/// Returns the name of the cache file for a request. The resolved request is
+/// hashed so that equivalent queries share a cache entry, e.g. "1 Jan 2019"
+/// and "2019-01-01". This includes the effect of --timezone and --dialect on
+/// the times. The `profile` and `region` should be the ones in effect after
+/// AWS_PROFILE and the shared config are applied, not only the arguments.
+pub fn cache_key(
+    request: &FilterLogEventsRequest,
+    limit: Option<i64>,
+    max_bytes: Option<u64>,
+    profile: Option<&str>,
+    region: Option<&str>,
+    endpoint: Option<&str>,
+) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    hasher.input(&[CACHE_KEY_VERSION]);
+    hasher.input(serde_json::to_string(request)?.as_bytes());
+    let options = (limit, max_bytes, profile, region, endpoint);
+    hasher.input(serde_json::to_string(&options)?.as_bytes());
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// Opens a cache file, decompressing it when the contents start with the gzip
+/// magic bytes. Returns `None` for files in a different format version. Files
+/// written before the header was added are read as is.
+pub fn open_cache(path: &Path) -> io::Result<Option<Box<dyn BufRead>>> {
+    let mut file = BufReader::new(File::open(path)?);
+    if file.fill_buf()?.starts_with(CACHE_MAGIC) {
+        let mut header = [0; CACHE_MAGIC.len() + 1];
+        file.read_exact(&mut header)?;
+        if header[CACHE_MAGIC.len()] != CACHE_FORMAT {
+            return Ok(None);
+        }
+    }
+    if file.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        Ok(Some(Box::new(BufReader::new(GzDecoder::new(file)))))
+    } else {
+        Ok(Some(Box::new(file)))
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Deletes all files in the cache directory, returning the number of files and
+/// bytes removed.
+pub fn clear_cache(cache_dir: &Path) -> Result<(u64, u64), CloudwatchError> {
+    let mut count = 0;
+    let mut bytes = 0;
+    if cache_dir.exists() {
+        let entries =
+            fs::read_dir(cache_dir).map_err(CloudwatchError::cache("could not read cache dir"))?;
+        for entry in entries {
+            let path = entry
+                .map_err(CloudwatchError::cache("could not read cache dir"))?
+                .path();
+            if path.is_file() {
+                bytes += fs::metadata(&path)
+                    .map_err(CloudwatchError::cache("could not read cache"))?
+                    .len();
+                fs::remove_file(&path).map_err(CloudwatchError::cache("could not delete cache"))?;
+                count += 1;
+            }
+        }
+    }
+    Ok((count, bytes))
+}
+
+/// The query that produced a cache entry, stored next to it in a `.meta` file
+/// since the name of the entry is a hash.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CacheMeta {
+    pub request: Value,
+    pub limit: Option<i64>,
+    pub profile: Option<String>,
+    pub region: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+}
+
+impl CacheMeta {
+    fn path(entry: &Path) -> PathBuf {
+        entry.with_extension("meta")
+    }
+
+    /// Writes the metadata of the cache entry at `entry`.
+    pub fn write(&self, entry: &Path) -> io::Result<()> {
+        fs::write(CacheMeta::path(entry), serde_json::to_vec(self)?)
+    }
+}
+
+/// A file in the cache directory, with the query that produced it when known.
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub modified: SystemTime,
+    pub size: u64,
+    pub meta: Option<CacheMeta>,
+}
+
+/// Lists the cache entries, oldest first. Entries from before metadata was
+/// stored have none.
+pub fn list_cache(cache_dir: &Path) -> Result<Vec<CacheEntry>, CloudwatchError> {
+    let mut entries = Vec::new();
+    if !cache_dir.exists() {
+        return Ok(entries);
+    }
+    for entry in
+        fs::read_dir(cache_dir).map_err(CloudwatchError::cache("could not read cache dir"))?
+    {
+        let path = entry
+            .map_err(CloudwatchError::cache("could not read cache dir"))?
+            .path();
+        if !path.is_file() || path.extension().is_some() {
+            continue;
+        }
+        let metadata =
+            fs::metadata(&path).map_err(CloudwatchError::cache("could not read cache"))?;
+        let meta = fs::read(CacheMeta::path(&path))
+            .ok()
+            .and_then(|x| serde_json::from_slice(&x).ok());
+        entries.push(CacheEntry {
+            modified: metadata
+                .modified()
+                .map_err(CloudwatchError::cache("could not read cache"))?,
+            size: metadata.len(),
+            meta,
+            path,
+        });
+    }
+    entries.sort_by_key(|x| x.modified);
+    Ok(entries)
+}
+
+/// Reads the events of cache files, which are each in timestamp order, and
+/// passes them to `f` merged by timestamp. Events in more than one file are
+/// passed once. Returns the number of malformed lines that were skipped.
+pub fn merge_cache<F>(paths: &[PathBuf], mut f: F) -> Result<u64, Error>
+where
+    F: FnMut(LogEvent) -> Result<(), Error>,
+{
+    let mut files = Vec::new();
+    for path in paths {
+        match open_cache(path).map_err(CloudwatchError::cache("could not read cache"))? {
+            Some(file) => files.push(file.split(b'\n')),
+            None => {
+                return Err(CloudwatchError::Args(format!(
+                    "{} was written by another version",
+                    path.display()
+                ))
+                .into())
+            }
+        }
+    }
+    let mut skipped = 0;
+    let mut next = |i: usize| -> Result<Option<LogEvent>, Error> {
+        for line in &mut files[i] {
+            let line = line.map_err(CloudwatchError::cache("could not read cache"))?;
+            match serde_json::from_str(&String::from_utf8_lossy(&line)) {
+                Ok(event) => return Ok(Some(event)),
+                Err(_) => skipped += 1,
+            }
+        }
+        Ok(None)
+    };
+    merge_sorted(paths.len(), &mut next, |event| f(event).map(|_| true))?;
+    Ok(skipped)
+}
+
+/// Merges `count` sources of events that are each in timestamp order, by
+/// always passing the earliest of their next events to `f` until it returns
+/// false. `next(i)` returns the next event of source `i`. Events with the same
+/// group and id are passed once.
+fn merge_sorted<N, F>(count: usize, mut next: N, mut f: F) -> Result<(), Error>
+where
+    N: FnMut(usize) -> Result<Option<LogEvent>, Error>,
+    F: FnMut(LogEvent) -> Result<bool, Error>,
+{
+    let mut heads = BinaryHeap::new();
+    let mut pending: Vec<Option<LogEvent>> = Vec::new();
+    for i in 0..count {
+        let event = next(i)?;
+        if let Some(event) = &event {
+            heads.push(cmp::Reverse((event.timestamp, i)));
+        }
+        pending.push(event);
+    }
+    // Duplicates have the same timestamp, so only the ids of the latest
+    // timestamp are kept
+    let mut last = (None, HashSet::new());
+    while let Some(cmp::Reverse((timestamp, i))) = heads.pop() {
+        let event = pending[i].take().unwrap();
+        if last.0 != timestamp {
+            last = (timestamp, HashSet::new());
+        }
+        let id = (event.log_group_name.clone(), event.event_id.clone());
+        if (id.1.is_none() || last.1.insert(id)) && !f(event)? {
+            return Ok(());
+        }
+        pending[i] = next(i)?;
+        if let Some(event) = &pending[i] {
+            heads.push(cmp::Reverse((event.timestamp, i)));
+        }
+    }
+    Ok(())
+}
+
+/// Parses a time given as epoch milliseconds or seconds, RFC 3339, a duration
+/// like "2h" before `now`, or in English like "yesterday" or "10 minutes ago",
+/// relative to `now`. The dialect decides whether dates like 03/04 are in March
+/// or April.
+pub fn parse_time<T: TimeZone>(
+    value: &str,
+    now: DateTime<T>,
+    dialect: Dialect,
+) -> Result<i64, String>
+where
+    T::Offset: Copy,
+{
+    if let Ok(epoch) = value.parse::<i64>() {
+        // Seconds only exceed this in the year 5138, milliseconds after 1973
+        return Ok(if epoch.abs() < 100_000_000_000 {
+            epoch * 1000
+        } else {
+            epoch
+        });
+    }
+    if let Ok(duration) = parse_duration(value) {
+        return Ok(now.timestamp_millis() - duration.as_millis() as i64);
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.timestamp_millis());
+    }
+    // ISO 8601 without an offset is taken as local time
+    if let Ok(time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f") {
+        if let Some(time) = now.timezone().from_local_datetime(&time).earliest() {
+            return Ok(time.timestamp_millis());
+        }
+    }
+    parse_date_string(value, now, dialect)
+        .map(|x| x.timestamp_millis())
+        .map_err(|e| e.to_string())
+}
+
+/// Parses a duration like `30s`, `5m`, `1h` or `2d`.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let error = || format!("invalid duration '{}'", value);
+    let split = value
+        .find(|x: char| !x.is_ascii_digit())
+        .ok_or_else(error)?;
+    let (amount, unit) = value.split_at(split);
+    let amount = amount.parse::<u64>().map_err(|_| error())?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(error()),
+    };
+    Ok(Duration::from_secs(amount * seconds))
+}
+
+/// Parses a size like `500`, `64k`, `10MB` or `1GiB` in bytes. Without an `i`
+/// the units are powers of 1000, with one powers of 1024.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let error = || format!("invalid size '{}'", value);
+    let split = value
+        .find(|x: char| !x.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount = amount.parse::<u64>().map_err(|_| error())?;
+    let unit = unit.trim_start().to_ascii_lowercase();
+    let unit = unit.strip_suffix('b').unwrap_or(&unit);
+    let (unit, base) = match unit.strip_suffix('i') {
+        Some(unit) => (unit, 1024u64),
+        None => (unit, 1000),
+    };
+    let exponent = match unit {
+        "" if base == 1000 => 0,
+        "k" => 1,
+        "m" => 2,
+        "g" => 3,
+        "t" => 4,
+        _ => return Err(error()),
+    };
+    amount.checked_mul(base.pow(exponent)).ok_or_else(error)
+}
+
+/// Formats a number of bytes in the largest binary unit that keeps it at
+/// least 1, e.g. `512 B`, `1.5 KiB` or `10.0 MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Parses a number of items like `500`, `5k` or `1M`, where the suffixes are
+/// powers of 1000 in either case.
+pub fn parse_count(value: &str) -> Result<i64, String> {
+    let error = || format!("invalid number '{}', expected e.g. 500, 5k or 1M", value);
+    let (amount, multiplier) = match value.chars().last() {
+        Some('k') | Some('K') => (&value[..value.len() - 1], 1000),
+        Some('m') | Some('M') => (&value[..value.len() - 1], 1_000_000),
+        _ => (value, 1),
+    };
+    amount
+        .parse::<i64>()
+        .ok()
+        .and_then(|x| x.checked_mul(multiplier))
+        .ok_or_else(error)
+}
+
+/// Returns the name of the profile in effect and where it came from: the given
+/// `profile`, AWS_PROFILE or the default.
+pub fn profile_name(profile: Option<&str>) -> (String, &'static str) {
+    match (profile, env::var("AWS_PROFILE")) {
+        (Some(profile), _) => (profile.to_string(), "--profile"),
+        (None, Ok(profile)) if !profile.is_empty() => (profile, "AWS_PROFILE"),
+        _ => ("default".to_string(), "default"),
+    }
+}
+
+/// Returns the region to use when none is given explicitly and where it came
+/// from: AWS_REGION or AWS_DEFAULT_REGION, then the region of the profile in
+/// the shared config file, then us-east-1. Unlike `Region::default()`, an
+/// invalid region is an error rather than silently replaced by us-east-1.
+pub fn default_region(profile: Option<&str>) -> Result<(Region, String), Error> {
+    for name in &["AWS_REGION", "AWS_DEFAULT_REGION"] {
+        match env::var(name) {
+            Ok(value) if !value.is_empty() => {
+                let region = value.parse().map_err(|e| {
+                    CloudwatchError::Args(format!("invalid region in {}: {}", name, e))
+                })?;
+                return Ok((region, name.to_string()));
+            }
+            _ => {}
+        }
+    }
+    let (profile, _) = profile_name(profile);
+    let path = match env::var_os("AWS_CONFIG_FILE") {
+        Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => dirs::home_dir().map(|x| x.join(".aws").join("config")),
+    };
+    match path.and_then(|x| config_region(&x, &profile)) {
+        Some(region) => {
+            let region = region.parse().map_err(|e| {
+                CloudwatchError::Args(format!(
+                    "invalid region in the config of profile '{}': {}",
+                    profile, e
+                ))
+            })?;
+            Ok((region, format!("config of profile {}", profile)))
+        }
+        None => Ok((Region::UsEast1, "default".to_string())),
+    }
+}
+
+/// Reads the region of `profile` from the shared config file at `path`, where
+/// profiles other than the default are in sections like `[profile name]`.
+fn config_region(path: &Path, profile: &str) -> Option<String> {
+    let contents = fs::read_to_string(path).ok()?;
+    let mut in_profile = false;
+    for line in contents.lines().map(str::trim) {
+        if let Some(section) = line.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+            let section = section.trim();
+            in_profile = section.strip_prefix("profile ").unwrap_or(section).trim() == profile;
+        } else if let (true, Some((key, value))) = (in_profile, line.split_once('=')) {
+            if key.trim() == "region" {
+                return Some(value.trim().to_string());
+            }
+        }
+    }
+    None
+}
+
+/// Describes where the credentials will be loaded from, following the order
+/// of `create_client` without loading them.
+pub fn credentials_source(profile: Option<&str>) -> String {
+    let is_set = |name| env::var(name).is_ok_and(|x: String| !x.is_empty());
+    if profile.is_none() && is_set("AWS_ACCESS_KEY_ID") && is_set("AWS_SECRET_ACCESS_KEY") {
+        return "AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY".to_string();
+    }
+    let (name, _) = profile_name(profile);
+    let section = format!("[{}]", name);
+    match ProfileProvider::new() {
+        Ok(provider)
+            if fs::read_to_string(provider.file_path())
+                .is_ok_and(|x| x.lines().any(|x| x.trim() == section)) =>
+        {
+            format!("profile {} in {}", name, provider.file_path().display())
+        }
+        _ if profile.is_some() => format!("profile {}, which isn't in the credentials file", name),
+        _ => "container or instance metadata".to_string(),
+    }
+}
+
+/// Creates a client using the credentials of the given profile, or the
+/// default credential chain when no profile is given. An explicit region
+/// always wins, otherwise `default_region` picks one. The timeout applies to
+/// both fetching credentials and API requests. A custom endpoint, e.g. for
+/// LocalStack, keeps the name of the region for signing requests.
+pub fn create_client(
+    profile: Option<&str>,
+    region: Option<Region>,
+    endpoint: Option<&str>,
+    timeout: Option<Duration>,
+) -> Result<Client, Error> {
+    let with_endpoint = |region: Region| match endpoint {
+        Some(endpoint) => Region::Custom {
+            name: region.name().to_string(),
+            endpoint: endpoint.to_string(),
+        },
+        None => region,
+    };
+    let dispatcher = TimeoutDispatcher {
+        client: HttpClient::new()
+            .map_err(|e| CloudwatchError::Aws(format!("could not create HTTP client: {}", e)))?,
+        timeout,
+    };
+    let region = with_endpoint(match region {
+        Some(region) => region,
+        None => default_region(profile)?.0,
+    });
+    match profile {
+        Some(profile) => {
+            let mut provider = ProfileProvider::new().map_err(|e| {
+                CloudwatchError::Aws(format!("could not load profile '{}': {}", profile, e))
+            })?;
+            provider.set_profile(profile);
+            Ok(Client {
+                logs: CloudWatchLogsClient::new_with(dispatcher, provider, region.clone()),
+                region,
+            })
+        }
+        None => {
+            let mut provider = ChainProvider::new();
+            if let Some(timeout) = timeout {
+                provider.set_timeout(timeout);
+            }
+            Ok(Client {
+                logs: CloudWatchLogsClient::new_with(
+                    dispatcher,
+                    AutoRefreshingProvider::new(provider).map_err(|e| {
+                        CloudwatchError::Aws(format!("could not load credentials: {}", e))
+                    })?,
+                    region.clone(),
+                ),
+                region,
+            })
+        }
+    }
+}
+
+/// A CloudWatch Logs client and the region it connects to.
+pub struct Client {
+    pub logs: CloudWatchLogsClient,
+    pub region: Region,
+}
+
+/// Dispatches requests with a default timeout.
+struct TimeoutDispatcher {
+    client: HttpClient,
+    timeout: Option<Duration>,
+}
+
+impl DispatchSignedRequest for TimeoutDispatcher {
+    type Future = HttpClientFuture;
+
+    fn dispatch(&self, request: SignedRequest, timeout: Option<Duration>) -> Self::Future {
+        self.client.dispatch(request, timeout.or(self.timeout))
+    }
+}
+
+/// Controls how `fetch_events` pages through results.
+#[derive(Clone, Copy)]
+pub struct Paging<'a> {
+    /// The maximum number of events to return.
+    pub max_items: Option<i64>,
+    /// Stop once the messages returned add up to this many bytes. Checked
+    /// after every page, which AWS limits to 1 MB, so the total can exceed it
+    /// by up to a page.
+    pub max_bytes: Option<u64>,
+    /// The number of events requested per page, at most 10000.
+    pub page_size: i64,
+    /// How often a throttled or failed request is retried.
+    pub max_retries: u32,
+    /// The number of sub-ranges fetched concurrently when the time range is
+    /// closed and unlimited.
+    pub jobs: usize,
+    pub progress: Option<&'a Progress>,
+}
+
+/// Reports the number of events and pages fetched on a single stderr line.
+pub struct Progress {
+    events: AtomicU64,
+    pages: AtomicU64,
+    start: Instant,
+    /// When the line was last updated, to limit how often it is redrawn.
+    shown: Mutex<Instant>,
+}
+
+impl Default for Progress {
+    fn default() -> Progress {
+        Progress::new()
+    }
+}
+
+impl Progress {
+    pub fn new() -> Progress {
+        let now = Instant::now();
+        Progress {
+            events: AtomicU64::new(0),
+            pages: AtomicU64::new(0),
+            start: now,
+            shown: Mutex::new(now),
+        }
+    }
+
+    pub fn page(&self, events: usize) {
+        let events = self.events.fetch_add(events as u64, Ordering::Relaxed) + events as u64;
+        let pages = self.pages.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut shown = self.shown.lock().unwrap();
+        if shown.elapsed() >= Duration::from_millis(100) {
+            *shown = Instant::now();
+            eprint!(
+                "\r{} events, {} pages, {}s elapsed",
+                events,
+                pages,
+                self.start.elapsed().as_secs()
+            );
+        }
+    }
+
+    /// Removes the progress line so it isn't mixed with other output.
+    pub fn clear(&self) {
+        if self.pages.load(Ordering::Relaxed) > 0 {
+            eprint!("\r\x1b[2K");
+        }
+    }
+}
+
+/// Pages through `filter_log_events`, calling `f` for every event until the
+/// results are exhausted, `max_items` events have been returned or the pages
+/// returned reach `max_bytes`. Returns the token for the next page when stopped
+/// early because of `max_items` or `max_bytes`.
+pub fn fetch_events<F>(
+    client: &Client,
+    request: FilterLogEventsRequest,
+    paging: Paging,
+    f: F,
+) -> Result<Option<String>, Error>
+where
+    F: FnMut(LogEvent) -> Result<(), Error>,
+{
+    if let (Some(start), Some(end), None, None, None) = (
+        request.start_time,
+        request.end_time,
+        paging.max_items,
+        paging.max_bytes,
+        &request.next_token,
+    ) {
+        if paging.jobs > 1 && end - start >= paging.jobs as i64 {
+            return fetch_parallel(client, request, paging, start, end, f).map(|_| None);
+        }
+    }
+    fetch_pages(client, request, paging, f)
+}
+
+/// Fetches the pages of `request` one after another. Returns the token for the
+/// next page when stopped early because of `max_items` or `max_bytes`.
+fn fetch_pages<F>(
+    client: &Client,
+    mut request: FilterLogEventsRequest,
+    paging: Paging,
+    mut f: F,
+) -> Result<Option<String>, Error>
+where
+    F: FnMut(LogEvent) -> Result<(), Error>,
+{
+    // Custom paging to avoid loading the entire data set into memory
+    let mut remaining = paging.max_items;
+    // Pages sometimes repeat events of the previous page, so the ids of the
+    // last page are kept to skip those
+    let mut previous = HashSet::new();
+    let mut bytes = 0;
+    let start = Instant::now();
+    let mut pages = 0;
+
+    while remaining.is_none() || remaining.unwrap() > 0 {
+        request.limit = Some(remaining.unwrap_or(paging.page_size).min(paging.page_size));
+        verbose!(2, "request: {}", serde_json::to_string(&request)?);
+
+        let mut attempt = 0;
+        let sent = Instant::now();
+        let response = loop {
+            match client.logs.filter_log_events(request.clone()).sync() {
+                Err(e) if attempt < paging.max_retries && is_transient(&e) => {
+                    verbose!(1, "retrying after {}", e);
+                    thread::sleep(retry_delay(attempt));
+                    attempt += 1;
+                }
+                result => break result.map_err(|e| filter_error(e, client, &request))?,
+            }
+        };
+        let events = response.events.unwrap_or_default();
+        pages += 1;
+        verbose!(
+            2,
+            "page {}: {} events in {:.2?}, next token {}",
+            pages,
+            events.len(),
+            sent.elapsed(),
+            response.next_token.as_deref().unwrap_or("none")
+        );
+        if let Some(progress) = paging.progress {
+            progress.page(events.len());
+        }
+
+        let mut current = HashSet::new();
+        for event in events {
+            if let Some(id) = &event.event_id {
+                if previous.contains(id) || !current.insert(id.clone()) {
+                    continue;
+                }
+            }
+            if let Some(count) = remaining {
+                remaining = Some(count - 1);
+            }
+            bytes += event.message.as_ref().map_or(0, |x| x.len() as u64);
+            f(LogEvent {
+                log_group_name: None,
+                event_id: event.event_id,
+                ingestion_time: event.ingestion_time,
+                log_stream_name: event.log_stream_name,
+                message: event.message,
+                timestamp: event.timestamp,
+            })?;
+        }
+        previous = current;
+
+        request.next_token = response.next_token;
+        if request.next_token.is_none() {
+            // At the end of the stream
+            break;
+        }
+        if paging.max_bytes.is_some_and(|x| bytes >= x) {
+            break;
+        }
+    }
+
+    verbose!(
+        1,
+        "fetched {} pages of {} in {:.2?}",
+        pages,
+        request.log_group_name,
+        start.elapsed()
+    );
+    Ok(request.next_token)
+}
+
+/// Calls `f` for all log groups starting with `prefix`.
+pub fn describe_groups<F>(client: &Client, prefix: Option<&str>, mut f: F) -> Result<(), Error>
+where
+    F: FnMut(rusoto_logs::LogGroup) -> Result<(), Error>,
+{
+    let mut request = DescribeLogGroupsRequest {
+        log_group_name_prefix: prefix.map(|x| x.to_string()),
+        ..Default::default()
+    };
+    loop {
+        let response = client
+            .logs
+            .describe_log_groups(request.clone())
+            .sync()
+            .map_err(aws_error)?;
+        for group in response.log_groups.unwrap_or_default() {
+            f(group)?;
+        }
+        request.next_token = response.next_token;
+        if request.next_token.is_none() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Passes the log streams of `group` that start with `prefix` to `f`, until it
+/// returns false.
+pub fn describe_streams<F>(
+    client: &Client,
+    group: &str,
+    prefix: Option<&str>,
+    mut f: F,
+) -> Result<(), Error>
+where
+    F: FnMut(rusoto_logs::LogStream) -> Result<bool, Error>,
+{
+    let mut request = DescribeLogStreamsRequest {
+        log_group_name: group.to_string(),
+        log_stream_name_prefix: prefix.map(|x| x.to_string()),
+        ..Default::default()
+    };
+    loop {
+        let response = client
+            .logs
+            .describe_log_streams(request.clone())
+            .sync()
+            .map_err(|e| match e {
+                RusotoError::Service(DescribeLogStreamsError::ResourceNotFound(_)) => {
+                    group_not_found(client, group)
+                }
+                e => aws_error(e),
+            })?;
+        for stream in response.log_streams.unwrap_or_default() {
+            if !f(stream)? {
+                return Ok(());
+            }
+        }
+        request.next_token = response.next_token;
+        if request.next_token.is_none() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the time of the most recent event in `group`, or only in the given
+/// streams or the streams starting with `prefix`. AWS can update the time of
+/// the last event up to an hour after it was ingested.
+pub fn last_event_time(
+    client: &Client,
+    group: &str,
+    streams: Option<&[&str]>,
+    prefix: Option<&str>,
+) -> Result<Option<i64>, Error> {
+    let describe = |request: &DescribeLogStreamsRequest| {
+        client
+            .logs
+            .describe_log_streams(request.clone())
+            .sync()
+            .map_err(|e| match e {
+                RusotoError::Service(DescribeLogStreamsError::ResourceNotFound(_)) => {
+                    group_not_found(client, group)
+                }
+                e => aws_error(e),
+            })
+    };
+    let request = DescribeLogStreamsRequest {
+        log_group_name: group.to_string(),
+        ..Default::default()
+    };
+    // AWS can only order by the last event when there is no prefix
+    let prefixes = match (streams, prefix) {
+        (Some(streams), _) => streams.to_vec(),
+        (None, Some(prefix)) => vec![prefix],
+        (None, None) => {
+            let response = describe(&DescribeLogStreamsRequest {
+                descending: Some(true),
+                limit: Some(1),
+                order_by: Some("LastEventTime".to_string()),
+                ..request
+            })?;
+            let streams = response.log_streams.unwrap_or_default();
+            return Ok(streams.into_iter().find_map(|x| x.last_event_timestamp));
+        }
+    };
+
+    let mut latest = None;
+    for prefix in prefixes {
+        let mut request = DescribeLogStreamsRequest {
+            log_stream_name_prefix: Some(prefix.to_string()),
+            ..request.clone()
+        };
+        loop {
+            let response = describe(&request)?;
+            for stream in response.log_streams.unwrap_or_default() {
+                // Other streams can start with the name of a stream
+                let name = stream.log_stream_name.as_deref().unwrap_or_default();
+                if streams.is_none() || name == prefix {
+                    latest = latest.max(stream.last_event_timestamp);
+                }
+            }
+            request.next_token = response.next_token;
+            if request.next_token.is_none() {
+                break;
+            }
+        }
+    }
+    Ok(latest)
+}
+
+/// Runs `requests` concurrently, e.g. for several groups or filter patterns,
+/// passing their events to `f` ordered by timestamp. Events returned by more
+/// than one request are passed once. With `tag_groups`, events are marked with
+/// their group. Each request returns at most `max_items` events and
+/// `max_bytes`, and so does the combined result. Returns whether events were
+/// left out because of `max_items` or `max_bytes`.
+pub fn fetch_merged<F>(
+    client: &Client,
+    requests: Vec<FilterLogEventsRequest>,
+    tag_groups: bool,
+    paging: Paging,
+    mut f: F,
+) -> Result<bool, Error>
+where
+    F: FnMut(LogEvent) -> Result<(), Error>,
+{
+    thread::scope(|scope| {
+        let mut receivers = Vec::new();
+        let truncated = Arc::new(AtomicBool::new(false));
+        for request in requests {
+            let group = request.log_group_name.clone();
+            // Bounded so that fast requests don't pile up in memory
+            let (sender, receiver) = mpsc::sync_channel(1000);
+            let truncated = truncated.clone();
+            scope.spawn(move || {
+                let result = fetch_events(client, request, paging, |event| {
+                    let event = LogEvent {
+                        log_group_name: Some(group.clone()).filter(|_| tag_groups),
+                        ..event
+                    };
+                    sender.send(Ok(event)).map_err(Error::new)
+                });
+                match result {
+                    Ok(Some(_)) => truncated.store(true, Ordering::SeqCst),
+                    Ok(None) => {}
+                    Err(e) => {
+                        let _ = sender.send(Err(e));
+                    }
+                }
+            });
+            receivers.push(receiver);
+        }
+
+        let mut remaining = paging.max_items;
+        let mut bytes = 0;
+        let mut stopped = false;
+        merge_sorted(
+            receivers.len(),
+            |i| receivers[i].recv().ok().transpose(),
+            |event| {
+                if remaining == Some(0) || paging.max_bytes.is_some_and(|x| bytes >= x) {
+                    stopped = true;
+                    return Ok(false);
+                }
+                bytes += event.message.as_ref().map_or(0, |x| x.len() as u64);
+                f(event)?;
+                remaining = remaining.map(|x| x - 1);
+                Ok(true)
+            },
+        )?;
+        Ok(stopped || truncated.load(Ordering::SeqCst))
+    })
+}
+
+/// Splits the range between `start` and `end` into `paging.jobs` windows that
+/// are fetched concurrently. Events outside of the window that returned them
+/// are dropped, so the windows never overlap. Events are passed to `f` in
+/// order, streaming the first window while the others are fetched ahead up to
+/// a buffer, after which they wait for their turn.
+pub fn fetch_parallel<F>(
+    client: &Client,
+    request: FilterLogEventsRequest,
+    paging: Paging,
+    start: i64,
+    end: i64,
+    mut f: F,
+) -> Result<(), Error>
+where
+    F: FnMut(LogEvent) -> Result<(), Error>,
+{
+    let jobs = paging.jobs as i64;
+    let width = (end - start + 1 + jobs - 1) / jobs;
+
+    thread::scope(|scope| {
+        let mut windows = Vec::new();
+        for i in 0..jobs {
+            // Both ends of a request are inclusive, so windows must not touch
+            let bounds = (start + i * width, (start + (i + 1) * width - 1).min(end));
+            let request = FilterLogEventsRequest {
+                start_time: Some(bounds.0),
+                end_time: Some(bounds.1),
+                ..request.clone()
+            };
+            // Bounded so that later windows aren't held in memory entirely,
+            // but large enough for a full page of each
+            let (sender, receiver) = mpsc::sync_channel(10_000);
+            scope.spawn(move || {
+                let result = fetch_pages(client, request, paging, |event| {
+                    if event
+                        .timestamp
+                        .is_some_and(|x| x < bounds.0 || x > bounds.1)
+                    {
+                        return Ok(());
+                    }
+                    // The receiver is gone when another window failed
+                    sender.send(Ok(event)).map_err(Error::new)
+                });
+                if let Err(e) = result {
+                    let _ = sender.send(Err(e));
+                }
+            });
+            windows.push(receiver);
+        }
+
+        for window in windows {
+            for event in window {
+                f(event?)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// A row of Logs Insights results, with the fields in the order of the query.
+pub type QueryRow = Vec<(String, String)>;
+
+/// Runs a Logs Insights query against every group and returns the rows of all
+/// groups in order. All queries are started before any is polled, so they run
+/// at the same time, and they are polled every `interval` until they finish.
+pub fn run_query(
+    client: &Client,
+    request: StartQueryRequest,
+    groups: &[String],
+    interval: Duration,
+) -> Result<Vec<QueryRow>, Error> {
+    let mut queries = Vec::new();
+    for group in groups {
+        let request = StartQueryRequest {
+            log_group_name: group.clone(),
+            ..request.clone()
+        };
+        let response = client
+            .logs
+            .start_query(request)
+            .sync()
+            .map_err(|e| match e {
+                RusotoError::Service(StartQueryError::ResourceNotFound(_)) => {
+                    group_not_found(client, group)
+                }
+                e => aws_error(e),
+            })?;
+        queries.push((group, response.query_id.unwrap_or_default()));
+    }
+
+    let mut rows = Vec::new();
+    for (group, query_id) in queries {
+        let request = GetQueryResultsRequest { query_id };
+        let response = loop {
+            let response = client
+                .logs
+                .get_query_results(request.clone())
+                .sync()
+                .map_err(aws_error)?;
+            match response.status.as_deref() {
+                Some("Complete") => break response,
+                Some("Scheduled") | Some("Running") | None => thread::sleep(interval),
+                Some(status) => {
+                    return Err(CloudwatchError::Aws(format!(
+                        "Query of log group '{}' ended with status {}",
+                        group, status
+                    ))
+                    .into())
+                }
+            }
+        };
+        for fields in response.results.unwrap_or_default() {
+            // @ptr only identifies the event for GetLogRecord
+            rows.push(
+                fields
+                    .into_iter()
+                    .filter_map(|x| Some((x.field?, x.value.unwrap_or_default())))
+                    .filter(|(field, _)| field != "@ptr")
+                    .collect(),
+            );
+        }
+    }
+    Ok(rows)
+}
+
+/// The longest wait before a retry, not counting the jitter.
+const MAX_RETRY_DELAY: u64 = 30_000;
+
+/// Returns how long to wait before retry `attempt`, counting from 0: 500 ms
+/// doubling every attempt up to `MAX_RETRY_DELAY`, plus up to 500 ms of
+/// jitter so that concurrent requests don't retry in lockstep.
+fn retry_delay(attempt: u32) -> Duration {
+    let delay = 500u64
+        .saturating_mul(1 << attempt.min(6))
+        .min(MAX_RETRY_DELAY);
+    let jitter = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |x| u64::from(x.subsec_nanos()) % 500);
+    Duration::from_millis(delay + jitter)
+}
+
+/// Returns whether a failed request may succeed when retried, i.e. when it
+/// was throttled or failed with a server error.
+fn is_transient(e: &RusotoError<FilterLogEventsError>) -> bool {
+    match e {
+        RusotoError::Service(FilterLogEventsError::ServiceUnavailable(_)) => true,
+        RusotoError::Unknown(response) => {
+            response.status.is_server_error()
+                || String::from_utf8_lossy(&response.body).contains("ThrottlingException")
+        }
+        _ => false,
+    }
+}
+
+/// Converts an error from `filter_log_events` into a readable message,
+/// explaining the common mistakes.
+fn filter_error(
+    e: RusotoError<FilterLogEventsError>,
+    client: &Client,
+    request: &FilterLogEventsRequest,
+) -> Error {
+    match e {
+        RusotoError::Service(FilterLogEventsError::ResourceNotFound(_)) => {
+            group_not_found(client, &request.log_group_name)
+        }
+        RusotoError::Service(FilterLogEventsError::InvalidParameter(message))
+            if request.filter_pattern.is_some() =>
+        {
+            CloudwatchError::Aws(format!(
+                "{}\nhint: check the filter pattern syntax at \
+                 https://docs.aws.amazon.com/AmazonCloudWatch/latest/logs/FilterAndPatternSyntax.html",
+                message
+            ))
+            .into()
+        }
+        e => aws_error(e),
+    }
+}
+
+pub fn group_not_found(client: &Client, group: &str) -> Error {
+    CloudwatchError::Aws(format!(
+        "Log group '{}' not found in region {}",
+        group,
+        client.region.name()
+    ))
+    .into()
+}
+
+/// Converts an AWS error into a readable message.
+pub fn aws_error<E: std::error::Error + Send + Sync + 'static>(e: RusotoError<E>) -> Error {
+    match e {
+        RusotoError::Credentials(_) => CloudwatchError::Aws(
+            "No AWS credentials found; configure via environment, profile, or IMDS".into(),
+        )
+        .into(),
+        e => CloudwatchError::Aws(e.to_string()).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::process;
+
+    use rusoto_core::signature::SignedRequestPayload;
+    use rusoto_mock::{MockCredentialsProvider, MockRequestDispatcher};
+    use serde_json::{json, Value};
+
+    /// Answers requests with the queued response bodies in order, and records
+    /// the bodies of the requests.
+    #[derive(Clone, Default)]
+    struct Pages {
+        responses: Arc<Mutex<VecDeque<Value>>>,
+        requests: Arc<Mutex<Vec<Value>>>,
+    }
+
+    impl Pages {
+        fn new(responses: Vec<Value>) -> Pages {
+            Pages {
+                responses: Arc::new(Mutex::new(responses.into())),
+                ..Pages::default()
+            }
+        }
+
+        fn requests(&self) -> Vec<Value> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        fn client(&self) -> Client {
+            Client {
+                logs: CloudWatchLogsClient::new_with(
+                    self.clone(),
+                    MockCredentialsProvider,
+                    Region::UsEast1,
+                ),
+                region: Region::UsEast1,
+            }
+        }
+    }
+
+    impl DispatchSignedRequest for Pages {
+        type Future = <MockRequestDispatcher as DispatchSignedRequest>::Future;
+
+        fn dispatch(&self, request: SignedRequest, timeout: Option<Duration>) -> Self::Future {
+            if let Some(SignedRequestPayload::Buffer(body)) = &request.payload {
+                let body = serde_json::from_slice(body).unwrap();
+                self.requests.lock().unwrap().push(body);
+            }
+            let mock = match self.responses.lock().unwrap().pop_front() {
+                Some(body) => MockRequestDispatcher::with_status(200).with_json_body(body),
+                None => MockRequestDispatcher::with_status(400).with_json_body(json!({
+                    "__type": "InvalidParameterException",
+                    "message": "unexpected request",
+                })),
+            };
+            mock.dispatch(request, timeout)
+        }
+    }
+
+    fn event(id: &str) -> Value {
+        json!({
+            "eventId": id,
+            "ingestionTime": 1_500_000_000_000i64,
+            "logStreamName": "stream",
+            "message": format!("message {}", id),
+            "timestamp": 1_500_000_000_000i64,
+        })
+    }
+
+    fn request() -> FilterLogEventsRequest {
+        FilterLogEventsRequest {
+            log_group_name: "group".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn paging(max_items: Option<i64>) -> Paging<'static> {
+        Paging {
+            max_items,
+            max_bytes: None,
+            page_size: 1000,
+            max_retries: 0,
+            jobs: 1,
+            progress: None,
+        }
+    }
+
+    fn fetch(pages: &Pages, max_items: Option<i64>) -> (Vec<String>, Option<String>) {
+        let mut ids = Vec::new();
+        let next_token = fetch_events(&pages.client(), request(), paging(max_items), |event| {
+            ids.extend(event.event_id);
+            Ok(())
+        })
+        .unwrap();
+        (ids, next_token)
+    }
+
+    #[test]
+    fn parallel_windows_drop_events_outside_their_bounds() {
+        let at = |id, timestamp: i64| {
+            let mut event = event(id);
+            event["timestamp"] = timestamp.into();
+            event
+        };
+        // Every window gets the events of both, whichever asks first
+        let page = json!({"events": [at("1", 5), at("2", 15)]});
+        let pages = Pages::new(vec![page.clone(), page]);
+        let mut ids = Vec::new();
+        let paging = Paging {
+            jobs: 2,
+            ..paging(None)
+        };
+        fetch_parallel(&pages.client(), request(), paging, 0, 19, |event| {
+            ids.extend(event.event_id);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(ids, ["1", "2"]);
+    }
+
+    #[test]
+    fn follows_next_tokens() {
+        let pages = Pages::new(vec![
+            json!({"events": [event("1"), event("2")], "nextToken": "t1"}),
+            json!({"events": [event("3")]}),
+        ]);
+        assert_eq!(
+            fetch(&pages, None),
+            (vec!["1".into(), "2".into(), "3".into()], None)
+        );
+
+        let requests = pages.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0]["limit"], 1000);
+        assert_eq!(requests[0].get("nextToken"), None);
+        assert_eq!(requests[1]["nextToken"], "t1");
+    }
+
+    #[test]
+    fn skips_events_repeated_across_pages() {
+        let pages = Pages::new(vec![
+            json!({"events": [event("1"), event("2")], "nextToken": "t1"}),
+            json!({"events": [event("2"), event("3")], "nextToken": "t2"}),
+            json!({"events": [event("3"), event("4")]}),
+        ]);
+        let (ids, _) = fetch(&pages, Some(4));
+        assert_eq!(ids, vec!["1", "2", "3", "4"]);
+        // Duplicates don't count towards the limit
+        assert_eq!(pages.requests()[2]["limit"], 1);
+    }
+
+    #[test]
+    fn merges_requests_without_duplicates() {
+        let pages = Pages::new(vec![
+            json!({"events": [event("1"), event("2")]}),
+            json!({"events": [event("2"), event("3")]}),
+        ]);
+        let requests = ["ERROR", "FATAL"]
+            .iter()
+            .map(|x| FilterLogEventsRequest {
+                filter_pattern: Some(x.to_string()),
+                ..request()
+            })
+            .collect();
+        let mut ids = Vec::new();
+        let truncated = fetch_merged(&pages.client(), requests, false, paging(None), |event| {
+            assert_eq!(event.log_group_name, None);
+            ids.extend(event.event_id);
+            Ok(())
+        })
+        .unwrap();
+        // The requests run concurrently, so either may get either page
+        ids.sort();
+        assert_eq!(ids, vec!["1", "2", "3"]);
+        assert!(!truncated);
+        assert_eq!(pages.requests().len(), 2);
+    }
+
+    #[test]
+    fn stops_at_max_bytes() {
+        let pages = Pages::new(vec![
+            json!({"events": [event("1"), event("2")], "nextToken": "t1"}),
+            json!({"events": [event("3"), event("4")], "nextToken": "t2"}),
+            json!({"events": [event("5")]}),
+        ]);
+        let paging = Paging {
+            // Every message is 9 bytes, so the second page reaches the cap
+            max_bytes: Some(20),
+            ..paging(None)
+        };
+        let mut ids = Vec::new();
+        let next_token = fetch_events(&pages.client(), request(), paging, |event| {
+            ids.extend(event.event_id);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(ids, vec!["1", "2", "3", "4"]);
+        assert_eq!(next_token.as_deref(), Some("t2"));
+    }
+
+    #[test]
+    fn finds_the_last_event() {
+        let stream = |name, last| json!({"logStreamName": name, "lastEventTimestamp": last});
+        let pages = Pages::new(vec![json!({"logStreams": [stream("b", 2000)]})]);
+        assert_eq!(
+            last_event_time(&pages.client(), "group", None, None).unwrap(),
+            Some(2000)
+        );
+        assert_eq!(pages.requests()[0]["orderBy"], "LastEventTime");
+
+        let pages = Pages::new(vec![
+            json!({"logStreams": [stream("a", 1000), stream("a2", 5000)], "nextToken": "t1"}),
+            json!({"logStreams": [stream("a3", 6000)]}),
+            json!({"logStreams": [stream("c", 3000)]}),
+        ]);
+        let streams = ["a", "c"];
+        assert_eq!(
+            last_event_time(&pages.client(), "group", Some(&streams), None).unwrap(),
+            Some(3000)
+        );
+        let requests = pages.requests();
+        assert_eq!(requests[1]["nextToken"], "t1");
+        assert_eq!(requests[2]["logStreamNamePrefix"], "c");
+    }
+
+    #[test]
+    fn caps_retry_delays() {
+        let first = retry_delay(0);
+        assert!(first >= Duration::from_millis(500) && first < Duration::from_millis(1000));
+        for &attempt in &[6, 20, 64, u32::MAX] {
+            let delay = retry_delay(attempt);
+            assert!(delay >= Duration::from_millis(MAX_RETRY_DELAY));
+            assert!(delay < Duration::from_millis(MAX_RETRY_DELAY + 500));
+        }
+    }
+
+    #[test]
+    fn parses_sizes_and_counts() {
+        assert_eq!(parse_size("500"), Ok(500));
+        assert_eq!(parse_size("64k"), Ok(64_000));
+        assert_eq!(parse_size("10MB"), Ok(10_000_000));
+        assert_eq!(parse_size("1GiB"), Ok(1 << 30));
+        assert_eq!(parse_size("2 KiB"), Ok(2048));
+        assert!(parse_size("10x").is_err());
+        assert!(parse_size("iB").is_err());
+        assert!(parse_size("99999999999T").is_err());
+        assert_eq!(parse_count("500"), Ok(500));
+        assert_eq!(parse_count("5k"), Ok(5000));
+        assert_eq!(parse_count("10K"), Ok(10_000));
+        assert_eq!(parse_count("1M"), Ok(1_000_000));
+        assert!(parse_count("k").is_err());
+        assert!(parse_count("1.5k").is_err());
+        assert!(parse_count("5x").is_err());
+    }
+
+    #[test]
+    fn formats_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(10 << 20), "10.0 MiB");
+        assert_eq!(format_bytes(3 << 40), "3.0 TiB");
+        assert_eq!(format_bytes(u64::MAX), "16777216.0 TiB");
+    }
+
+    #[test]
+    fn stops_at_max_items() {
+        let pages = Pages::new(vec![
+            json!({"events": [event("1"), event("2")], "nextToken": "t1"}),
+            json!({"events": [event("3")]}),
+        ]);
+        let (ids, next_token) = fetch(&pages, Some(2));
+        assert_eq!(ids, vec!["1", "2"]);
+        assert_eq!(next_token.as_deref(), Some("t1"));
+
+        let requests = pages.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0]["limit"], 2);
+    }
+
+    #[test]
+    fn reports_missing_groups() {
+        let client = Client {
+            logs: CloudWatchLogsClient::new_with(
+                MockRequestDispatcher::with_status(400).with_json_body(json!({
+                    "__type": "ResourceNotFoundException",
+                    "message": "The specified log group does not exist.",
+                })),
+                MockCredentialsProvider,
+                Region::EuWest1,
+            ),
+            region: Region::EuWest1,
+        };
+        let e = fetch_events(&client, request(), paging(None), |_| Ok(())).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Log group 'group' not found in region eu-west-1"
+        );
+    }
+
+    #[test]
+    fn polls_queries_until_complete() {
+        let field = |field, value| json!({"field": field, "value": value});
+        let pages = Pages::new(vec![
+            json!({"queryId": "a"}),
+            json!({"queryId": "b"}),
+            json!({"status": "Running"}),
+            json!({"status": "Complete", "results": [
+                [field("@timestamp", "2017-07-14 02:40:00.000"), field("@ptr", "x")],
+            ]}),
+            json!({"status": "Complete", "results": [[field("count", "2")]]}),
+        ]);
+        let request = StartQueryRequest {
+            query_string: "fields @timestamp".to_string(),
+            start_time: 1_500_000_000,
+            end_time: 1_500_003_600,
+            ..Default::default()
+        };
+        let groups = ["a".to_string(), "b".to_string()];
+        let rows = run_query(&pages.client(), request, &groups, Duration::from_millis(0)).unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                vec![(
+                    "@timestamp".to_string(),
+                    "2017-07-14 02:40:00.000".to_string()
+                )],
+                vec![("count".to_string(), "2".to_string())],
+            ]
+        );
+        let requests = pages.requests();
+        assert_eq!(requests[1]["logGroupName"], "b");
+        assert_eq!(requests[1]["startTime"], 1_500_000_000);
+        assert_eq!(requests[2]["queryId"], "a");
+        assert_eq!(requests[4]["queryId"], "b");
+
+        let pages = Pages::new(vec![json!({"queryId": "a"}), json!({"status": "Failed"})]);
+        let e = run_query(
+            &pages.client(),
+            StartQueryRequest::default(),
+            &groups[..1],
+            Duration::from_millis(0),
+        )
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Query of log group 'a' ended with status Failed"
+        );
+    }
+
+    #[test]
+    fn parses_times() {
+        let now = Utc.timestamp_millis(1_500_000_000_000);
+        let parse = |value| parse_time(value, now, Dialect::Uk);
+        assert_eq!(parse("1500000000"), Ok(1_500_000_000_000));
+        assert_eq!(parse("1500000000123"), Ok(1_500_000_000_123));
+        assert_eq!(parse("2h"), Ok(1_500_000_000_000 - 2 * 3_600_000));
+        assert_eq!(parse("2017-07-14T02:40:00.5Z"), Ok(1_500_000_000_500));
+        assert_eq!(parse("2017-07-14T02:40:00"), Ok(1_500_000_000_000));
+        assert_eq!(parse("10 minutes ago"), Ok(1_500_000_000_000 - 600_000));
+        assert!(parse("yestrday").is_err());
+
+        let e = CloudwatchError::DateParse("yestrday".into(), parse("yestrday").unwrap_err());
+        assert!(e
+            .to_string()
+            .starts_with("could not parse time 'yestrday': "));
+        assert!(e
+            .to_string()
+            .ends_with("Examples: '2h', 'yesterday 9am', '2024-01-01'."));
+    }
+
+    #[test]
+    fn parses_log_group_arns() {
+        let group = LogGroupArn {
+            region: "eu-west-1",
+            account: "123456789012",
+            name: "/aws/lambda/function",
+        };
+        assert_eq!(
+            LogGroupArn::parse(
+                "arn:aws:logs:eu-west-1:123456789012:log-group:/aws/lambda/function:*"
+            )
+            .unwrap(),
+            Some(group)
+        );
+        assert!(
+            LogGroupArn::parse("arn:aws-cn:logs:cn-north-1:123456789012:log-group:group")
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(LogGroupArn::parse("group").unwrap(), None);
+        assert!(LogGroupArn::parse("arn:aws:s3:::bucket").is_err());
+    }
+
+    /// A directory for the files of one test, removed again afterwards.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = env::temp_dir().join(format!("cloudwatch-{}-{}", name, process::id()));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn cache_keys_are_stable() {
+        let request = FilterLogEventsRequest {
+            start_time: Some(1_500_000_000_000),
+            end_time: Some(1_500_003_600_000),
+            filter_pattern: Some("ERROR".to_string()),
+            interleaved: Some(true),
+            ..request()
+        };
+        let key = cache_key(
+            &request,
+            Some(100),
+            None,
+            Some("dev"),
+            Some("eu-west-1"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            key,
+            cache_key(
+                &request,
+                Some(100),
+                None,
+                Some("dev"),
+                Some("eu-west-1"),
+                None
+            )
+            .unwrap()
+        );
+        // Changing this discards the caches of all users, see CACHE_KEY_VERSION
+        assert_eq!(
+            key,
+            "902faa95332608d3c98f5dbf2e268b78507a26cf36bffeb575a87b997e276177"
+        );
+
+        let other = cache_key(&request, None, None, Some("dev"), Some("eu-west-1"), None).unwrap();
+        assert_ne!(key, other);
+        let other = cache_key(
+            &request,
+            Some(100),
+            None,
+            Some("dev"),
+            Some("eu-west-1"),
+            Some("http://localhost:4566"),
+        );
+        assert_ne!(key, other.unwrap());
+        let other = cache_key(
+            &request,
+            Some(100),
+            Some(1_000_000),
+            Some("dev"),
+            Some("eu-west-1"),
+            None,
+        );
+        assert_ne!(key, other.unwrap());
+    }
+
+    #[test]
+    fn reads_regions_without_changing_the_environment() {
+        let profile = env::var_os("AWS_PROFILE");
+        let dir = TempDir::new("config");
+        let path = dir.0.join("config");
+        fs::write(
+            &path,
+            "[default]\nregion = ap-southeast-1\n\n[profile prod]\noutput = json\nregion=eu-central-1\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config_region(&path, "default").as_deref(),
+            Some("ap-southeast-1")
+        );
+        assert_eq!(
+            config_region(&path, "prod").as_deref(),
+            Some("eu-central-1")
+        );
+        assert_eq!(config_region(&path, "dev"), None);
+        assert_eq!(config_region(&dir.0.join("missing"), "default"), None);
+        assert_eq!(env::var_os("AWS_PROFILE"), profile);
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let dir = TempDir::new("cache");
+        for &compress in &[false, true] {
+            let partial = dir.0.join("entry.partial");
+            let path = dir.0.join("entry");
+            let mut file = PartialFile::create(partial.clone(), compress).unwrap();
+            file.write_all(b"first\nsecond\n").unwrap();
+            file.persist(&path).unwrap();
+            assert!(!partial.exists());
+
+            let lines: Vec<String> = open_cache(&path)
+                .unwrap()
+                .unwrap()
+                .lines()
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(lines, vec!["first", "second"]);
+        }
+    }
+
+    #[test]
+    fn cache_entries_merge_by_timestamp() {
+        let dir = TempDir::new("merge");
+        let write = |name: &str, events: &[(&str, i64)]| {
+            let path = dir.0.join(name);
+            let mut file = PartialFile::create(path.with_extension("partial"), true).unwrap();
+            for (id, timestamp) in events {
+                let event = json!({"eventId": id, "timestamp": timestamp});
+                writeln!(file, "{}", event).unwrap();
+            }
+            file.write_all(b"malformed\n").unwrap();
+            file.persist(&path).unwrap();
+            path
+        };
+        let paths = [
+            write("a", &[("1", 100), ("3", 300), ("4", 300)]),
+            write("b", &[("2", 200), ("4", 300), ("5", 400)]),
+        ];
+        let mut ids = Vec::new();
+        let skipped = merge_cache(&paths, |event| {
+            ids.extend(event.event_id);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(ids, vec!["1", "2", "3", "4", "5"]);
+        assert_eq!(skipped, 2);
+    }
+
+    #[test]
+    fn cache_lists_entries_with_their_query() {
+        let dir = TempDir::new("list");
+        fs::write(dir.0.join("old"), "first\n").unwrap();
+        fs::write(dir.0.join("new.partial"), "").unwrap();
+        let path = dir.0.join("new");
+        fs::write(&path, "first\nsecond\n").unwrap();
+        CacheMeta {
+            request: serde_json::to_value(request()).unwrap(),
+            limit: Some(10),
+            profile: None,
+            region: Some("eu-west-1".to_string()),
+            endpoint: None,
+        }
+        .write(&path)
+        .unwrap();
+
+        let mut entries = list_cache(&dir.0).unwrap();
+        entries.sort_by_key(|x| x.path.clone());
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, path);
+        assert_eq!(entries[0].size, 13);
+        let meta = entries[0].meta.as_ref().unwrap();
+        assert_eq!(meta.request["logGroupName"], "group");
+        assert_eq!(meta.limit, Some(10));
+        assert!(entries[1].meta.is_none());
+    }
+
+    #[test]
+    fn cache_removes_unfinished_files() {
+        let dir = TempDir::new("partial");
+        let partial = dir.0.join("entry.partial");
+        let mut file = PartialFile::create(partial.clone(), true).unwrap();
+        file.write_all(b"first\n").unwrap();
+        drop(file);
+        assert!(!partial.exists());
+    }
+
+    #[test]
+    fn cache_ignores_other_versions() {
+        let dir = TempDir::new("version");
+        let path = dir.0.join("entry");
+        let mut contents = CACHE_MAGIC.to_vec();
+        contents.push(CACHE_FORMAT + 1);
+        fs::write(&path, contents).unwrap();
+        assert!(open_cache(&path).unwrap().is_none());
+
+        // Files from before the header are read as is
+        fs::write(&path, "first\n").unwrap();
+        let mut contents = String::new();
+        open_cache(&path)
+            .unwrap()
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "first\n");
+    }
+}
@@ -1,218 +1,4872 @@
-#[macro_use]
-extern crate serde_derive;
-
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
-use std::path::Path;
-use std::{fs, io};
+use std::io::{BufRead, BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use std::{cmp, env, fs, io, process, thread};
+
+use anyhow::Error;
 
-use chrono::{Local, TimeZone};
-use chrono_english::{parse_date_string, Dialect};
-use clap::{App, Arg, ArgGroup};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, TimeZone, Utc};
+use chrono_english::Dialect;
+use chrono_tz::Tz;
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, ErrorKind, Shell, SubCommand};
 use colored::*;
-use crypto::digest::Digest;
-use crypto::sha1::Sha1;
-use rusoto_core::Region;
-use rusoto_logs::{CloudWatchLogs, CloudWatchLogsClient, FilterLogEventsRequest};
-
-#[derive(Debug, Deserialize, Serialize)]
-struct LogEvent {
-    #[serde(rename = "eventId")]
-    event_id: Option<String>,
-    #[serde(rename = "ingestionTime")]
-    ingestion_time: Option<i64>,
-    #[serde(rename = "logStreamName")]
-    log_stream_name: Option<String>,
-    #[serde(rename = "message")]
-    message: Option<String>,
-    #[serde(rename = "timestamp")]
-    timestamp: Option<i64>,
-}
-
-const NEWLINE: &[u8] = &['\n' as u8];
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use regex::{Regex, RegexBuilder};
+use rusoto_core::{Region, RusotoError};
+use rusoto_logs::{
+    CloudWatchLogs, DescribeLogStreamsError, DescribeLogStreamsRequest, FilterLogEventsRequest,
+    StartQueryRequest,
+};
+use serde_json::{json, Value};
+
+use cloudwatch::{
+    aws_error, cache_key, clear_cache, create_client, credentials_source, default_region,
+    describe_groups, describe_streams, fetch_events, fetch_merged, format_bytes, group_not_found,
+    last_event_time, list_cache, merge_cache, open_cache, parse_count, parse_duration, parse_size,
+    profile_name, run_query, verbose, CacheMeta, Client, CloudwatchError, LogEvent, LogGroup,
+    LogGroupArn, LogStream, Paging, PartialFile, Progress, QueryRow, Zone, REGIONS, VERBOSITY,
+};
+
+const NEWLINE: &[u8] = b"\n";
+
+/// Set by --quiet to keep stderr free of anything but errors.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Like `eprintln!`, but for warnings and summaries that --quiet suppresses.
+macro_rules! notice {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Prints events in the requested format, skipping those rejected by the
+/// local filter.
+struct Printer {
+    output: Output,
+    writer: RefCell<Box<dyn OutputWriter>>,
+    filter: EventFilter,
+    text: TextOptions,
+    /// The number of events printed so far.
+    count: Cell<u64>,
+    /// The total size of their messages.
+    size: Cell<u64>,
+    /// Holds back events for --reverse and --tail until they are released.
+    held: RefCell<Option<Held>>,
+    /// The earliest and latest timestamp printed so far.
+    span: Cell<Option<(i64, i64)>>,
+    /// Print a summary to stderr when finished.
+    stats: bool,
+    /// Exit with EXIT_EMPTY when finished without printing any events.
+    exit_on_empty: bool,
+    /// Count events per window instead of printing them.
+    rate: Option<Rate>,
+    /// Also print the events around matches.
+    context: Option<Context>,
+    /// Matches control characters and terminal escapes to remove from messages.
+    strip: Option<Regex>,
+    /// Matches the log streams whose events are left out.
+    exclude_stream: Option<Regex>,
+    /// Collapse identical messages, for --unique.
+    unique: Option<Unique>,
+    progress: Option<Progress>,
+}
+
+/// The most recent matching events, up to `capacity`.
+struct Held {
+    events: VecDeque<LogEvent>,
+    capacity: Option<usize>,
+    /// Release the events newest-first.
+    reverse: bool,
+    /// Order the events by ingestion time instead of as fetched.
+    by_ingestion: bool,
+}
+
+/// The events around matches of the local filters, for -A and -B.
+struct Context {
+    before: usize,
+    after: usize,
+    /// The latest events that didn't match, printed if the next one does.
+    recent: RefCell<VecDeque<LogEvent>>,
+    /// The number of events still to print after the last match.
+    remaining: Cell<usize>,
+    /// Whether events were left out since the last one printed.
+    skipped: Cell<bool>,
+}
+
+/// The number of matching events per window of time, for --rate.
+struct Rate {
+    /// The length of a window in milliseconds.
+    window: i64,
+    /// The counts by the start of their window.
+    counts: RefCell<BTreeMap<i64, u64>>,
+    /// The start of the next window to print.
+    next: Cell<Option<i64>>,
+}
+
+/// The events written so far with the number of times their message occurred,
+/// for --unique.
+struct Unique {
+    /// Collapse all events with the same message, not only consecutive ones.
+    global: bool,
+    /// The first event of every message in the order they occurred, or only
+    /// the latest one unless global.
+    events: RefCell<Vec<(LogEvent, u64)>>,
+    /// The position of every message in `events`, when global.
+    index: RefCell<HashMap<String, usize>>,
+}
+
+impl Unique {
+    fn new(global: bool) -> Unique {
+        Unique {
+            global,
+            events: RefCell::new(Vec::new()),
+            index: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Counts an event, returning the previous message and its count once a
+    /// different one follows it.
+    fn push(&self, event: &LogEvent) -> Option<(LogEvent, u64)> {
+        let mut events = self.events.borrow_mut();
+        if self.global {
+            let message = event.message.clone().unwrap_or_default();
+            let mut index = self.index.borrow_mut();
+            match index.get(&message) {
+                Some(&i) => events[i].1 += 1,
+                None => {
+                    index.insert(message, events.len());
+                    events.push((event.clone(), 1));
+                }
+            }
+            return None;
+        }
+        match events.last_mut() {
+            Some((last, count)) if last.message == event.message => {
+                *count += 1;
+                None
+            }
+            _ => {
+                let previous = events.pop();
+                events.push((event.clone(), 1));
+                previous
+            }
+        }
+    }
+
+    /// Takes the messages not written yet.
+    fn take(&self) -> Vec<(LogEvent, u64)> {
+        self.index.borrow_mut().clear();
+        self.events.borrow_mut().drain(..).collect()
+    }
+}
+
+impl Printer {
+    fn print<W: Write>(&self, out: &mut W, event: &LogEvent) -> Result<(), Error> {
+        if let (Some(exclude), Some(stream)) = (&self.exclude_stream, &event.log_stream_name) {
+            if exclude.is_match(stream) {
+                return Ok(());
+            }
+        }
+        let stripped;
+        let event = match (&self.strip, &event.message) {
+            (Some(strip), Some(message)) => {
+                stripped = LogEvent {
+                    message: Some(strip.replace_all(message, "").into_owned()),
+                    ..event.clone()
+                };
+                &stripped
+            }
+            _ => event,
+        };
+        match &mut *self.held.borrow_mut() {
+            Some(held) => {
+                if !self.filter.matches(event) {
+                    return Ok(());
+                }
+                if held.capacity.is_some_and(|x| held.events.len() >= x) {
+                    held.events.pop_front();
+                }
+                if held.capacity != Some(0) {
+                    held.events.push_back(event.clone());
+                }
+                Ok(())
+            }
+            None => self.write(out, event),
+        }
+    }
+
+    /// Prints the count of every window from the one containing `start` that
+    /// ended by `until`, including empty ones.
+    fn print_rates<W: Write>(&self, out: &mut W, start: i64, until: i64) -> Result<(), Error> {
+        if let Some(rate) = &self.rate {
+            let mut next = rate
+                .next
+                .get()
+                .unwrap_or(start - start.rem_euclid(rate.window));
+            while next + rate.window <= until {
+                let count = rate.counts.borrow_mut().remove(&next).unwrap_or(0);
+                if self.output == Output::Json {
+                    serde_json::to_writer(&mut *out, &json!({"timestamp": next, "count": count}))?;
+                    out.write_all(NEWLINE)?;
+                } else {
+                    writeln!(out, "{} {}", format_time(next, &self.text).green(), count)?;
+                }
+                next += rate.window;
+            }
+            rate.next.set(Some(next));
+        }
+        Ok(())
+    }
+
+    /// Writes the events held back so far, and any later ones immediately.
+    fn release<W: Write>(&self, out: &mut W) -> Result<(), Error> {
+        if let Some(mut held) = self.held.borrow_mut().take() {
+            if held.by_ingestion {
+                // Stable, so events ingested together keep their order
+                held.events
+                    .make_contiguous()
+                    .sort_by_key(|x| x.ingestion_time);
+            }
+            while let Some(event) = if held.reverse {
+                held.events.pop_back()
+            } else {
+                held.events.pop_front()
+            } {
+                self.write(out, &event)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write<W: Write>(&self, out: &mut W, event: &LogEvent) -> Result<(), Error> {
+        let context = match &self.context {
+            Some(context) => context,
+            None if self.filter.matches(event) => return self.emit(out, event),
+            None => return Ok(()),
+        };
+        if self.filter.matches(event) {
+            // Like grep, groups of events that aren't adjacent are separated
+            if context.skipped.get()
+                && self.count.get() > 0
+                && matches!(self.output, Output::Text | Output::Raw | Output::Template)
+            {
+                writeln!(out, "--")?;
+            }
+            let recent: Vec<LogEvent> = context.recent.borrow_mut().drain(..).collect();
+            for event in recent.iter().chain(Some(event)) {
+                self.emit(out, event)?;
+            }
+            context.skipped.set(false);
+            context.remaining.set(context.after);
+        } else if context.remaining.get() > 0 {
+            context.remaining.set(context.remaining.get() - 1);
+            self.emit(out, event)?;
+        } else {
+            let mut recent = context.recent.borrow_mut();
+            if recent.len() >= context.before {
+                context.skipped.set(true);
+                recent.pop_front();
+            }
+            if context.before > 0 {
+                recent.push_back(event.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes an event regardless of the filter.
+    fn emit<W: Write>(&self, out: &mut W, event: &LogEvent) -> Result<(), Error> {
+        match &self.rate {
+            Some(rate) => {
+                if let Some(timestamp) = event.timestamp {
+                    let start = timestamp - timestamp.rem_euclid(rate.window);
+                    *rate.counts.borrow_mut().entry(start).or_insert(0) += 1;
+                }
+            }
+            None => match &self.unique {
+                Some(unique) => {
+                    if let Some((event, count)) = unique.push(event) {
+                        self.writer
+                            .borrow_mut()
+                            .write_counted(out, &event, count, &self.text)?;
+                    }
+                }
+                None => self
+                    .writer
+                    .borrow_mut()
+                    .write_event(out, event, &self.text)?,
+            },
+        }
+        self.record(event);
+        Ok(())
+    }
+
+    /// Updates the statistics for an event that was written.
+    fn record(&self, event: &LogEvent) {
+        self.count.set(self.count.get() + 1);
+        self.size
+            .set(self.size.get() + event.message.as_ref().map_or(0, |x| x.len() as u64));
+        if let Some(timestamp) = event.timestamp {
+            self.span.set(Some(match self.span.get() {
+                Some((first, last)) => (first.min(timestamp), last.max(timestamp)),
+                None => (timestamp, timestamp),
+            }));
+        }
+    }
+
+    /// Whether cached NDJSON can be copied to the output as is.
+    fn is_passthrough(&self) -> bool {
+        self.output == Output::Json
+            && self.filter.is_empty()
+            && self.held.borrow().is_none()
+            && self.strip.is_none()
+            && self.exclude_stream.is_none()
+            && self.unique.is_none()
+            && !self.stats
+            && self.text.fields.is_none()
+            && !self.text.json_indent
+    }
+
+    /// Writes the start of the output, see `OutputWriter::write_header`.
+    fn write_header<W: Write>(&self, out: &mut W, envelope: Option<&Value>) -> Result<(), Error> {
+        self.writer
+            .borrow_mut()
+            .write_header(out, &self.text, envelope)
+    }
+
+    /// Writes whatever is needed to complete the output and closes it.
+    /// `truncated` tells whether events were left out because of
+    /// `--max-items`, when known.
+    fn finish<W: Close>(&self, out: &mut W, truncated: Option<bool>) -> Result<(), Error> {
+        self.release(out)?;
+        if let Some(unique) = &self.unique {
+            for (event, count) in unique.take() {
+                self.writer
+                    .borrow_mut()
+                    .write_counted(out, &event, count, &self.text)?;
+            }
+        }
+        self.writer.borrow_mut().finish(out, truncated)?;
+        out.close()?;
+        if let Some(progress) = &self.progress {
+            progress.clear();
+        }
+
+        if self.stats {
+            let mut summary = match self.count.get() {
+                1 => "1 event".to_string(),
+                n => format!("{} events", n),
+            };
+            summary += &format!(" ({})", format_size(self.size.get(), self.text.raw_bytes));
+            if let Some((first, last)) = self.span.get() {
+                summary += &format!(
+                    " from {} to {}",
+                    format_time(first, &self.text),
+                    format_time(last, &self.text)
+                );
+            }
+            match truncated {
+                Some(true) => summary += ", truncated by --max-items",
+                Some(false) => summary += ", complete",
+                None => {}
+            }
+            notice!("{}", summary);
+        }
+
+        // An empty result is easily mistaken for a failure
+        if self.count.get() == 0 {
+            if self.output != Output::Count && !self.stats {
+                notice!("No events matched in the given range.");
+            }
+            if self.exit_on_empty {
+                return Err(Empty.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Filters events locally after they have been retrieved.
+struct EventFilter {
+    grep: Option<Regex>,
+    invert: bool,
+    /// Conditions that JSON messages must all satisfy.
+    json: Vec<JsonFilter>,
+    /// The earliest and latest timestamp of matching events, for narrowing
+    /// down results that have already been retrieved.
+    after: Option<i64>,
+    before: Option<i64>,
+}
+
+impl EventFilter {
+    fn is_empty(&self) -> bool {
+        self.grep.is_none() && self.json.is_empty() && self.after.is_none() && self.before.is_none()
+    }
+
+    fn matches(&self, event: &LogEvent) -> bool {
+        let timestamp = event.timestamp.unwrap_or_default();
+        if self.after.is_some_and(|x| timestamp < x) || self.before.is_some_and(|x| timestamp > x) {
+            return false;
+        }
+        let message = event.message.as_deref().unwrap_or("");
+        if let Some(grep) = &self.grep {
+            if grep.is_match(message) == self.invert {
+                return false;
+            }
+        }
+        if self.json.is_empty() {
+            return true;
+        }
+        match serde_json::from_str::<Value>(message) {
+            Ok(value) => self.json.iter().all(|x| x.matches(&value)),
+            Err(_) => false,
+        }
+    }
+}
+
+/// A condition on a JSON message, like `.level == "ERROR"`, `.user.id` or
+/// `.status >= 500`.
+struct JsonFilter {
+    path: Vec<String>,
+    /// Without a comparison the field only needs to be present and not null.
+    comparison: Option<(Comparison, Value)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl JsonFilter {
+    fn parse(expression: &str) -> Result<JsonFilter, String> {
+        let expression = expression.trim();
+        if !expression.starts_with('.') {
+            return Err(format!(
+                "'{}' must start with a path like .level",
+                expression
+            ));
+        }
+        let end = expression
+            .find(|x: char| x.is_whitespace() || "=!<>".contains(x))
+            .unwrap_or(expression.len());
+        let (path, rest) = expression.split_at(end);
+        let path = path[1..]
+            .split('.')
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_string())
+            .collect();
+
+        let rest = rest.trim_start();
+        if rest.is_empty() {
+            return Ok(JsonFilter {
+                path,
+                comparison: None,
+            });
+        }
+        let operators = [
+            ("==", Comparison::Eq),
+            ("!=", Comparison::Ne),
+            ("<=", Comparison::Le),
+            (">=", Comparison::Ge),
+            ("<", Comparison::Lt),
+            (">", Comparison::Gt),
+        ];
+        let (operator, comparison) = operators
+            .iter()
+            .find(|(x, _)| rest.starts_with(x))
+            .ok_or_else(|| format!("expected ==, !=, <, <=, > or >= instead of '{}'", rest))?;
+        let value = rest[operator.len()..].trim();
+        let value = serde_json::from_str(value)
+            .map_err(|_| format!("'{}' is not a JSON value, strings need quotes", value))?;
+        Ok(JsonFilter {
+            path,
+            comparison: Some((*comparison, value)),
+        })
+    }
+
+    fn matches(&self, message: &Value) -> bool {
+        let mut value = message;
+        for key in &self.path {
+            let next = match value {
+                Value::Object(map) => map.get(key),
+                Value::Array(items) => key.parse::<usize>().ok().and_then(|x| items.get(x)),
+                _ => None,
+            };
+            match next {
+                Some(next) => value = next,
+                None => return false,
+            }
+        }
+        let (comparison, expected) = match &self.comparison {
+            Some(x) => x,
+            None => return !value.is_null(),
+        };
+        let ordering = match (value, expected) {
+            (Value::Number(a), Value::Number(b)) => a.as_f64().partial_cmp(&b.as_f64()),
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            _ if value == expected => Some(cmp::Ordering::Equal),
+            _ => None,
+        };
+        match comparison {
+            Comparison::Eq => ordering == Some(cmp::Ordering::Equal),
+            Comparison::Ne => ordering != Some(cmp::Ordering::Equal),
+            Comparison::Lt => ordering == Some(cmp::Ordering::Less),
+            Comparison::Le => matches!(ordering, Some(cmp::Ordering::Less | cmp::Ordering::Equal)),
+            Comparison::Gt => ordering == Some(cmp::Ordering::Greater),
+            Comparison::Ge => {
+                matches!(
+                    ordering,
+                    Some(cmp::Ordering::Greater | cmp::Ordering::Equal)
+                )
+            }
+        }
+    }
+}
+
+/// The format used to print events.
+#[derive(Clone, Copy, PartialEq)]
+enum Output {
+    /// One JSON object per line, also used for the cache.
+    Json,
+    /// A single JSON array, streamed as events arrive.
+    JsonArray,
+    Text,
+    Csv,
+    /// Only the message of every event.
+    Raw,
+    /// Events formatted with --template.
+    Template,
+    /// Only the number of events, printed when finished.
+    Count,
+}
+
+/// A piece of a --template string.
+enum TemplatePart {
+    Text(String),
+    /// A placeholder like `{message}`.
+    Field(String),
+}
+
+/// The placeholders that can be used in a template.
+const TEMPLATE_FIELDS: &[&str] = &[
+    "time",
+    "timestamp",
+    "group",
+    "stream",
+    "message",
+    "eventId",
+    "ingestionTime",
+];
+
+/// Parses a template like `{time} [{stream}] {message}`. Braces are escaped
+/// by doubling them.
+fn parse_template(template: &str) -> Result<Vec<TemplatePart>, String> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut field = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => field.push(c),
+                        None => return Err("unclosed '{', use '{{' for a literal brace".into()),
+                    }
+                }
+                if !TEMPLATE_FIELDS.contains(&field.as_str()) {
+                    return Err(format!(
+                        "unknown placeholder '{{{}}}', expected one of {}",
+                        field,
+                        TEMPLATE_FIELDS.join(", ")
+                    ));
+                }
+                if !text.is_empty() {
+                    parts.push(TemplatePart::Text(std::mem::take(&mut text)));
+                }
+                parts.push(TemplatePart::Field(field));
+            }
+            '}' => return Err("unmatched '}', use '}}' for a literal brace".to_string()),
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        parts.push(TemplatePart::Text(text));
+    }
+    Ok(parts)
+}
+
+/// Controls how events are formatted.
+struct TextOptions {
+    /// Pretty-print messages that contain JSON.
+    pretty: bool,
+    /// A strftime format for timestamps, RFC3339 with milliseconds when absent.
+    time_format: Option<String>,
+    /// Terms to highlight in messages.
+    highlight: Vec<String>,
+    /// The time zone timestamps are shown in.
+    zone: Zone,
+    /// Show the log stream of events in text mode, padded or truncated to this
+    /// width when it isn't zero.
+    show_stream: Option<usize>,
+    /// Show when events were ingested and how long after their timestamp.
+    show_ingestion: bool,
+    /// Color lines by severity, read from these JSON fields or from keywords
+    /// in the message.
+    severity: Option<Vec<String>>,
+    /// The format used by template output.
+    template: Vec<TemplatePart>,
+    /// The fields included in JSON and CSV output, all when absent.
+    fields: Option<Vec<String>>,
+    csv: Csv,
+    /// Show sizes as a number of bytes instead of in units like KiB.
+    raw_bytes: bool,
+    /// Pretty-print the JSON objects of events.
+    json_indent: bool,
+}
+
+/// How CSV output is written.
+struct Csv {
+    delimiter: char,
+    /// Start with a row of field names.
+    header: bool,
+    /// Start with a UTF-8 byte order mark, which Excel needs to detect the
+    /// encoding.
+    bom: bool,
+}
+
+/// The fields of an event, in the order they are serialized.
+const FIELDS: &[&str] = &[
+    "logGroupName",
+    "eventId",
+    "ingestionTime",
+    "logStreamName",
+    "message",
+    "timestamp",
+];
+
+/// ANSI escape sequences, then control characters other than tabs and newlines.
+const CONTROL: &str = r"\x1b\[[0-?]*[ -/]*[@-~]|\x1b[@-_]|[\x00-\x08\x0b-\x1f\x7f\u{80}-\u{9f}]";
+
+/// The most log streams a single request can name.
+const MAX_STREAM_NAMES: usize = 100;
+/// The most requests --exclude-stream splits the remaining streams into,
+/// beyond which excluded events are dropped locally instead.
+const MAX_STREAM_REQUESTS: usize = 10;
+
+/// Exit code for failures that don't fit any other category.
+const EXIT_FAILURE: i32 = 1;
+/// Exit code for invalid command line arguments.
+const EXIT_USAGE: i32 = 2;
+/// Exit code for errors returned by AWS.
+const EXIT_AWS: i32 = 3;
+/// Exit code for a query without results, with --exit-on-empty.
+const EXIT_EMPTY: i32 = 4;
 
 fn main() {
-    let matches = App::new("cloudwatch")
+    if let Err(e) = run() {
+        // Like other Unix tools, stop quietly when the reader went away, e.g.
+        // after `| head`
+        let broken_pipe = e.chain().any(|x| {
+            x.downcast_ref::<io::Error>()
+                .is_some_and(|x| x.kind() == io::ErrorKind::BrokenPipe)
+        });
+        if broken_pipe {
+            process::exit(0);
+        }
+        if e.downcast_ref::<Empty>().is_some() {
+            process::exit(EXIT_EMPTY);
+        }
+        eprintln!("error: {:#}", e);
+        let code = match e.chain().find_map(|x| x.downcast_ref::<CloudwatchError>()) {
+            Some(CloudwatchError::Args(_)) | Some(CloudwatchError::DateParse(..)) => EXIT_USAGE,
+            Some(CloudwatchError::Aws(_)) => EXIT_AWS,
+            _ => EXIT_FAILURE,
+        };
+        process::exit(code);
+    }
+}
+
+/// Returned when no events matched with --exit-on-empty, so the output is
+/// completed before exiting with EXIT_EMPTY.
+#[derive(Debug)]
+struct Empty;
+
+impl fmt::Display for Empty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("no events matched")
+    }
+}
+
+impl std::error::Error for Empty {}
+
+/// Defines the command line arguments.
+fn app() -> App<'static, 'static> {
+    App::new("cloudwatch")
         .version("1.0")
         .about("Does great things!")
+        .after_help(
+            "EXIT CODES:\n    \
+             1    Unexpected failure, e.g. when reading or writing the cache\n    \
+             2    Invalid arguments\n    \
+             3    Error returned by AWS\n    \
+             4    No events matched, with --exit-on-empty",
+        )
         .arg(
             Arg::with_name("start-time")
                 .long("start-time")
                 .alias("since")
                 .short("S")
-                .help("The start of the time range. Events before this time are not returned.")
+                .help("The start of the time range. Events before this time are not returned. Times can be given as epoch seconds or milliseconds, ISO 8601, a duration like 2h before now, or in English like \"yesterday\" or \"10 minutes ago\".")
                 .takes_value(true),
         )
         .arg(
             Arg::with_name("end-time")
                 .long("end-time")
                 .alias("until")
-                .help("The end of the time range. Events later than this time are not returned.")
+                .help("The end of the time range. Events later than this time are not returned. Results are only cached when this is an absolute time.")
                 .short("U")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("relative-to")
+                .long("relative-to")
+                .takes_value(true)
+                .possible_values(&["now", "last-event"])
+                .default_value("now")
+                .help("What relative times like 1h or \"10 minutes ago\" count back from. last-event uses the most recent event of the group or streams, for workloads that stopped logging. AWS can report that event up to an hour late."),
+        )
+        .arg(
+            Arg::with_name("for")
+                .long("for")
+                .takes_value(true)
+                .validator(|x| parse_duration(&x).map(|_| ()))
+                .help("The length of the time range, e.g. 30m, counted from --start-time or back from --end-time."),
+        )
         .arg(
             Arg::with_name("max-items")
                 .long("max-items")
-                .alias("lines")
+                .aliases(&["lines", "limit"])
+                .visible_alias("head")
                 .short("n")
                 .takes_value(true)
-                .help("The total number of items to return in the command's output."),
+                .validator(|x| parse_count(&x).map(|_| ()))
+                .help("The total number of items to return in the command's output, e.g. 500, 5k or 1M. These are the FIRST items of the time range, like head and unlike tail -n; use --tail for the last ones. Without --start-time or --end-time, only events from the --default-range are returned."),
+        )
+        .arg(
+            Arg::with_name("limit-bytes")
+                .long("limit-bytes")
+                .takes_value(true)
+                .value_name("size")
+                .validator(|x| parse_size(&x).map(|_| ()))
+                .help("Stop fetching once the messages add up to this size, e.g. 10MB or 512KiB. Checked after every page, so the output can exceed it by up to a page of 1 MB. Combines with --max-items, whichever is reached first stops."),
+        )
+        .arg(
+            Arg::with_name("default-range")
+                .long("default-range")
+                .takes_value(true)
+                .default_value("24h")
+                .validator(|x| parse_duration(&x).map(|_| ()))
+                .help("How far back to look when only --max-items or --tail is given, e.g. 30m, 6h or 7d."),
+        )
+        .arg(
+            Arg::with_name("snap")
+                .long("snap")
+                .takes_value(true)
+                .value_name("granularity")
+                .validator(|x| match parse_duration(&x) {
+                    Ok(x) if x.as_millis() == 0 => Err("must be more than 0".into()),
+                    result => result.map(|_| ()),
+                })
+                .help("Round the start and end time down to a multiple of this duration, e.g. 1m or 5m. Relative times then resolve to the same range until the next multiple, so their results are cached as well, at the cost of missing up to this much of the most recent events. Without --end-time, the range ends now, snapped down the same way, except with --follow."),
+        )
+        .arg(
+            Arg::with_name("allow-future")
+                .long("allow-future")
+                .help("Keep an end time in the future. Otherwise it is moved back to now with a warning, since it usually comes from mixed up time zones or relative times, and a range that lies entirely in the future is rejected."),
+        )
+        .arg(
+            Arg::with_name("follow")
+                .long("follow")
+                .short("F")
+                .help("Keep polling for new events after the existing ones have been printed. Results are not cached."),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .takes_value(true)
+                .default_value("5")
+                .validator(|x| match x.parse::<u64>() {
+                    Ok(0) => Err("must be at least 1".to_string()),
+                    result => result.map(|_| ()).map_err(|e| e.to_string()),
+                })
+                .help("The number of seconds to wait between polls in follow mode, at least 1."),
+        )
+        .arg(
+            Arg::with_name("rate")
+                .long("rate")
+                .takes_value(true)
+                .value_name("window")
+                .validator(|x| parse_duration(&x).map(|_| ()))
+                .help("In follow mode, print the number of matching events per window, e.g. 1m, instead of the events. Every window is printed once it has passed, with its start time and count."),
         )
         .group(
             ArgGroup::with_name("limit")
-                .args(&["start-time", "end-time", "max-items"])
+                .args(&["start-time", "end-time", "max-items", "tail", "follow", "list-groups", "list-streams", "merge", "print-config", "explain-filter"])
                 .multiple(true)
                 .required(true),
         )
         .arg(
             Arg::with_name("log-group-name")
-                .required(true)
+                .required_unless_one(&["list-groups", "group-prefix", "merge", "print-config"])
+                .conflicts_with_all(&["group-prefix", "merge"])
+                .takes_value(true)
+                .help("The name or ARN of the log group. An ARN also selects the region, but its account is ignored: the group of that name is queried in the account of the credentials."),
+        )
+        .arg(
+            Arg::with_name("merge")
+                .long("merge")
+                .takes_value(true)
+                .multiple(true)
+                .value_name("entry")
+                .help("Print the events of cached results instead of querying AWS, merged by timestamp and without duplicates. An entry is a cache key, the start of one as shown by cache list, or the path of a file. Local filters and output options apply as usual."),
+        )
+        .arg(
+            Arg::with_name("group-prefix")
+                .long("group-prefix")
                 .takes_value(true)
-                .help("The name of the log group."),
+                .help("Query all log groups starting with this prefix instead of a single group. Events are merged by timestamp and marked with their group. Results are not cached."),
         )
         .arg(
             Arg::with_name("log-stream-name")
+                .long("log-stream-name")
                 .short("M")
                 .takes_value(true)
-                .help("The name of the log stream."),
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .help("The name of the log stream. Can be repeated or comma-separated."),
+        )
+        .arg(
+            Arg::with_name("exclude-stream")
+                .long("exclude-stream")
+                .takes_value(true)
+                .value_name("regex")
+                .conflicts_with("log-stream-name")
+                .validator(|x| Regex::new(&x).map(|_| ()).map_err(|e| e.to_string()))
+                .help("Leave out the log streams whose name matches this regex, e.g. health-check. The other streams of the group, or of --stream-prefix, are listed and queried by name, up to 1000 of them. With more streams, --follow or --group-prefix, every stream is queried and the excluded events are dropped locally. Results are not cached."),
+        )
+        .arg(
+            Arg::with_name("stream-prefix")
+                .long("stream-prefix")
+                .takes_value(true)
+                .conflicts_with("log-stream-name")
+                .help("Only return events from log streams that start with this prefix."),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Print the resolved request and cache file to stderr without querying AWS."),
         )
         .arg(
             Arg::with_name("force")
                 .short("f")
                 .help("Retreive data even if cached."),
         )
+        .arg(
+            Arg::with_name("no-cache")
+                .long("no-cache")
+                .conflicts_with("force")
+                .help("Neither read nor write the cache."),
+        )
+        .arg(
+            Arg::with_name("offline")
+                .long("offline")
+                .conflicts_with_all(&["force", "no-cache"])
+                .help("Only read results from the cache and never contact AWS. Fails when the query isn't cached, even if the cache has expired."),
+        )
+        .arg(
+            Arg::with_name("no-compress")
+                .long("no-compress")
+                .help("Store new cache entries uncompressed instead of gzipped."),
+        )
+        .arg(
+            Arg::with_name("max-age")
+                .long("max-age")
+                .takes_value(true)
+                .validator(|x| parse_duration(&x).map(|_| ()))
+                .help("Ignore cached results older than this, e.g. 30s, 5m, 1h or 2d."),
+        )
         .arg(
             Arg::with_name("text")
                 .long("text")
-                .help("Return results as text instead of JSON.")
+                .help("Return results as text instead of JSON. Same as --output text.")
                 .short("t"),
         )
-        .arg(Arg::with_name("filter-pattern").help("The filter pattern to use."))
-        .get_matches();
-
-    let log_group_name = matches.value_of("log-group-name").unwrap();
-    let log_stream_name = matches.value_of("log-stream-name");
-    let filter_pattern = matches.value_of("filter-pattern");
-    let start_time = matches.value_of("start-time");
-    let end_time = matches.value_of("end-time");
-    let max_items = matches.value_of("max-items");
-    let show_text = matches.is_present("text");
-    let force = matches.is_present("force");
-
-    let hash = {
-        let mut hasher = Sha1::new();
-        hasher.input(&[2]); // version
-        hasher.input_str(log_group_name);
-        hasher.input_str(log_stream_name.unwrap_or("log-stream-name"));
-        hasher.input_str(filter_pattern.unwrap_or("filter-pattern"));
-        hasher.input_str(start_time.unwrap_or("start-time"));
-        hasher.input_str(end_time.unwrap_or("end-time"));
-        hasher.input_str(max_items.unwrap_or("max-items"));
-        hasher.result_str()
-    };
-
-    let cache_dir = dirs::cache_dir().unwrap().join(Path::new("cloudwatch"));
-    fs::create_dir_all(&cache_dir).expect("could not create cache dir");
-    let path = cache_dir.join(Path::new(&hash));
-
-    let stdout = io::stdout();
-    let mut stdout = stdout.lock();
-
-    // Check cache first
-    if !force && path.exists() {
-        let mut file = File::open(path).unwrap();
-        if show_text {
-            let file = BufReader::new(file);
-            for line in file.lines() {
-                let string = line.unwrap();
-                let value = serde_json::from_str(&string).unwrap();
-                print_event(value);
-            }
-        } else {
-            io::copy(&mut file, &mut stdout).unwrap();
-        }
-        return;
-    }
-
-    let now = Local::now();
-    let to_timestamp = |x| {
-        parse_date_string(x, now, Dialect::Uk)
-            .unwrap()
-            .timestamp_millis()
-    };
-
-    let client = CloudWatchLogsClient::new(Region::default());
-
-    let temporary_path = path.with_extension("partial");
-    let mut file = File::create(temporary_path.clone()).unwrap();
-
-    // Custom paging to avoid loading the entire data set into memory
-    let mut remaining = max_items.map(|x| x.parse::<i64>().unwrap());
-    let mut next_token = None;
-
-    while remaining.is_none() || remaining.unwrap() > 0 {
-        let event = FilterLogEventsRequest {
-            end_time: end_time.map(to_timestamp),
-            filter_pattern: filter_pattern.map(|x| x.to_string()),
-            interleaved: Some(true),
-            limit: Some(remaining.unwrap_or(1000).min(1000)),
-            log_group_name: log_group_name.to_string(),
-            log_stream_name_prefix: None,
-            log_stream_names: log_stream_name.map(|x| vec![x.to_string()]),
-            next_token,
-            start_time: start_time.map(to_timestamp),
-        };
-
-        let response = client.filter_log_events(event).sync();
-        if let Err(e) = response {
-            std::io::stderr()
-                .write(format!("{:?}", e).as_bytes())
-                .unwrap();
-            return;
-        }
-
-        let response = response.unwrap();
-        let events = response.events.unwrap();
-
-        if let Some(count) = remaining {
-            remaining = Some(count - events.len() as i64);
-        }
-
-        for event in events {
-            let event = LogEvent {
-                event_id: event.event_id,
-                ingestion_time: event.ingestion_time,
-                log_stream_name: event.log_stream_name,
-                message: event.message,
-                timestamp: event.timestamp,
-            };
-
-            let json = serde_json::to_string(&event).unwrap();
-            let bytes = json.as_bytes();
-            file.write(bytes).unwrap();
-            file.write(NEWLINE).unwrap();
-            if show_text {
-                print_event(event);
-            } else {
-                stdout.write(bytes).unwrap();
-                stdout.write(NEWLINE).unwrap();
-            }
-        }
-
-        next_token = response.next_token;
-        if next_token.is_none() {
-            // At the end of the stream
-            break;
-        }
-    }
-
-    fs::rename(temporary_path, path).unwrap();
-}
-
-fn print_event(event: LogEvent) {
-    let timestamp = event.timestamp.unwrap();
-    let message = event.message.unwrap();
-    let time = Local.timestamp_millis(timestamp);
-    println!("{} {}", time.to_rfc3339().green(), message);
+        .arg(
+            Arg::with_name("raw")
+                .long("raw")
+                .conflicts_with_all(&["text", "output"])
+                .help("Print only the message of every event. Same as --output raw."),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .possible_values(&["json", "ndjson", "json-array", "text", "csv", "raw", "template"])
+                .conflicts_with("text")
+                .help("The format of the output. Defaults to ndjson (or json), which prints one JSON object per line with the fields eventId, ingestionTime, logStreamName, message and timestamp. json-array prints the same objects as a single JSON array, raw prints only the messages and template uses --template."),
+        )
+        .arg(
+            Arg::with_name("output-file")
+                .long("output-file")
+                .takes_value(true)
+                .value_name("path")
+                .help("Write the results to this file instead of stdout. An existing file is overwritten."),
+        )
+        .arg(
+            Arg::with_name("pager")
+                .long("pager")
+                .help("Show the output in $PAGER, or less -R when it isn't set, if stdout is a terminal. less keeps colors and exits right away when the output fits on the screen unless LESS is set."),
+        )
+        .arg(
+            Arg::with_name("gzip")
+                .long("gzip")
+                .conflicts_with("pager")
+                .help("Compress what is written to stdout with gzip, in any output format, e.g. to stream it into aws s3 cp - s3://bucket/key. --output-file is written uncompressed."),
+        )
+        .arg(
+            Arg::with_name("tee")
+                .long("tee")
+                .help("Write the results to stdout as well as to --output-file."),
+        )
+        .arg(
+            Arg::with_name("rotate")
+                .long("rotate")
+                .takes_value(true)
+                .value_name("size")
+                .validator(|x| parse_size(&x).map(|_| ()))
+                .help("With --follow, rotate --output-file once it reaches this size, e.g. 100MB. The full file is renamed to <path>.1 after moving older files to <path>.2 and so on."),
+        )
+        .arg(
+            Arg::with_name("template")
+                .long("template")
+                .takes_value(true)
+                .conflicts_with_all(&["text", "raw"])
+                .validator(|x| parse_template(&x).map(|_| ()))
+                .help("The format of every event, e.g. \"{time} [{stream}] {message}\". The placeholders are time, timestamp, group, stream, message, eventId and ingestionTime. Implies --output template."),
+        )
+        .arg(
+            Arg::with_name("envelope")
+                .long("envelope")
+                .help("Print a single JSON object with the resolved query and region, the events as an array, their count and whether they were truncated by --max-items. The truncation is null when the results came from the cache."),
+        )
+        .arg(
+            Arg::with_name("json-indent")
+                .long("json-indent")
+                .help("Pretty-print the JSON object of every event over several lines, with a blank line between them. Unlike --pretty, this formats the events and not their messages."),
+        )
+        .arg(
+            Arg::with_name("json-compact")
+                .long("json-compact")
+                .conflicts_with("json-indent")
+                .help("Print every JSON object on a single line. This is the default, for scripts that want to be explicit."),
+        )
+        .arg(
+            Arg::with_name("count")
+                .long("count")
+                .conflicts_with_all(&["text", "raw", "output", "template"])
+                .help("Only print the number of matching events."),
+        )
+        .arg(
+            Arg::with_name("fields")
+                .long("fields")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .validator(|x| {
+                    if FIELDS.contains(&x.as_str()) {
+                        Ok(())
+                    } else {
+                        Err(format!("unknown field '{}', expected one of {}", x, FIELDS.join(", ")))
+                    }
+                })
+                .help("The fields to include in JSON and CSV output, e.g. timestamp,message."),
+        )
+        .arg(
+            Arg::with_name("csv-delim")
+                .long("csv-delim")
+                .takes_value(true)
+                .value_name("char")
+                .validator(|x| match x.as_str() {
+                    "tab" | "\\t" => Ok(()),
+                    _ if x.chars().count() == 1 && !"\"\r\n".contains(&*x) => Ok(()),
+                    _ => Err("expected a single character other than a quote, or tab".to_string()),
+                })
+                .help("The delimiter of CSV fields, e.g. ; for spreadsheets in locales with a decimal comma, or tab. Defaults to a comma."),
+        )
+        .arg(
+            Arg::with_name("no-header")
+                .long("no-header")
+                .help("Don't start CSV output with a row of field names."),
+        )
+        .arg(
+            Arg::with_name("bom")
+                .long("bom")
+                .help("Start CSV output with a UTF-8 byte order mark, so Excel detects the encoding."),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .global(true)
+                .takes_value(true)
+                .help("Use a specific profile from your credential file. Overrides AWS_PROFILE and credentials in AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY."),
+        )
+        .arg(
+            Arg::with_name("region")
+                .long("region")
+                .global(true)
+                .takes_value(true)
+                .validator(|x| match x.parse::<Region>() {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(format!("{}. Valid regions are: {}", e, REGIONS.join(", "))),
+                })
+                .help("The region to use. Without it, AWS_REGION, AWS_DEFAULT_REGION and the region of the profile in the config file are tried in that order, and then us-east-1."),
+        )
+        .arg(
+            Arg::with_name("endpoint-url")
+                .long("endpoint-url")
+                .global(true)
+                .takes_value(true)
+                .env("AWS_ENDPOINT_URL")
+                .help("The URL of the CloudWatch Logs API, e.g. http://localhost:4566 for LocalStack."),
+        )
+        .arg(
+            Arg::with_name("tail")
+                .long("tail")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|x| x.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Print the LAST N matching events of the time range, oldest first, like tail -n. -n, --max-items and --head keep the first N instead. The whole time range is retrieved while the last N events are kept in memory. Without --start-time or --end-time, the --default-range is used. With --follow, this applies to the events before following."),
+        )
+        .arg(
+            Arg::with_name("reverse")
+                .long("reverse")
+                .short("r")
+                .help("Print the most recent events first. The whole time range is retrieved and the last --max-items events are kept in memory until it completes, so without --max-items every event is held in memory."),
+        )
+        .arg(
+            Arg::with_name("sort-by")
+                .long("sort-by")
+                .takes_value(true)
+                .possible_values(&["timestamp", "ingestion"])
+                .default_value("timestamp")
+                .help("The order of the events. ingestion orders them by the time AWS received them, to analyze ingestion delays. That order is only known once every event has been retrieved, so all of them are held in memory, or the last --tail of them."),
+        )
+        .arg(
+            Arg::with_name("resume-token")
+                .long("resume-token")
+                .takes_value(true)
+                .help("Continue a query that was truncated by --max-items, using the token it printed. The other arguments must be the same."),
+        )
+        .arg(
+            Arg::with_name("resume-token-file")
+                .long("resume-token-file")
+                .takes_value(true)
+                .conflicts_with("resume-token")
+                .help("Like --resume-token, but reads the token from a file written by --save-token."),
+        )
+        .arg(
+            Arg::with_name("save-token")
+                .long("save-token")
+                .takes_value(true)
+                .help("Write the token to continue a truncated query to this file. The file is removed when no events are left."),
+        )
+        .arg(
+            Arg::with_name("exit-on-empty")
+                .long("exit-on-empty")
+                .help("Exit with code 4 when no events matched, to tell an empty result apart from a failure in scripts."),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .short("v")
+                .multiple(true)
+                .global(true)
+                .conflicts_with("quiet")
+                .help("Print diagnostics to stderr: cache decisions and timing, and with -vv every request and page."),
+        )
+        .arg(
+            Arg::with_name("print-config")
+                .long("print-config")
+                .help("Print the profile, region and source of credentials that would be used, and where each was taken from, without contacting AWS. A log group ARN selects the region as well."),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .short("q")
+                .global(true)
+                .help("Don't print warnings or summaries to stderr, only errors."),
+        )
+        .arg(
+            Arg::with_name("progress")
+                .long("progress")
+                .help("Show the number of events and pages fetched so far on stderr, when it is a terminal."),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .long("stats")
+                .help("Print the number of events, the size of their messages and the time span they cover to stderr when done."),
+        )
+        .arg(
+            Arg::with_name("show-stream")
+                .long("show-stream")
+                .help("Show the log stream of every event in text mode."),
+        )
+        .arg(
+            Arg::with_name("strip-control")
+                .long("strip-control")
+                .help("Remove control characters and terminal escape sequences, like colors, from messages. Tabs and newlines are kept."),
+        )
+        .arg(
+            Arg::with_name("show-ingestion")
+                .long("show-ingestion")
+                .help("Show when every event was ingested in text mode, and how long after its timestamp. Delays of a minute or more are shown in red."),
+        )
+        .arg(
+            Arg::with_name("stream-width")
+                .long("stream-width")
+                .takes_value(true)
+                .default_value("24")
+                .validator(|x| x.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("The width of the stream name shown by --show-stream. Longer names are shortened from the start, 0 shows them in full."),
+        )
+        .arg(
+            Arg::with_name("pretty")
+                .long("pretty")
+                .help("Pretty-print messages that contain JSON."),
+        )
+        .arg(
+            Arg::with_name("severity")
+                .long("severity")
+                .help("Color lines red or yellow by their severity in text output. The severity is read from the level or severity field of JSON messages, or from keywords like ERROR and WARN."),
+        )
+        .arg(
+            Arg::with_name("severity-field")
+                .long("severity-field")
+                .takes_value(true)
+                .value_name("name")
+                .multiple(true)
+                .number_of_values(1)
+                .help("The JSON field that holds the severity of messages, instead of level and severity. Implies --severity."),
+        )
+        .arg(
+            Arg::with_name("time-format")
+                .long("time-format")
+                .takes_value(true)
+                .validator(|x| {
+                    if StrftimeItems::new(&x).any(|x| x == Item::Error) {
+                        Err(format!("invalid format string '{}'", x))
+                    } else {
+                        Ok(())
+                    }
+                })
+                .help("The strftime format used for timestamps. Defaults to RFC3339 with milliseconds."),
+        )
+        .arg(
+            Arg::with_name("dialect")
+                .long("dialect")
+                .global(true)
+                .takes_value(true)
+                .possible_values(&["us", "uk"])
+                .help("Whether dates like 03/04 mean March 4 (us) or 3 April (uk). Defaults to us for an en_US locale and uk otherwise."),
+        )
+        .arg(
+            Arg::with_name("timezone")
+                .long("timezone")
+                .global(true)
+                .alias("tz")
+                .takes_value(true)
+                .validator(|x| x.parse::<Tz>().map(|_| ()))
+                .help("The time zone used to interpret times without an offset and to display timestamps, e.g. UTC or Europe/Amsterdam. Defaults to the system time zone."),
+        )
+        .arg(
+            Arg::with_name("no-highlight")
+                .long("no-highlight")
+                .help("Don't highlight the terms of the filter pattern in text output."),
+        )
+        .arg(
+            Arg::with_name("color")
+                .long("color")
+                .takes_value(true)
+                .possible_values(&["auto", "always", "never"])
+                .default_value("auto")
+                .help("When to use colors. With auto, colors are used when stdout is a terminal and NO_COLOR isn't set."),
+        )
+        .arg(
+            Arg::with_name("grep")
+                .long("grep")
+                .takes_value(true)
+                .validator(|x| Regex::new(&x).map(|_| ()).map_err(|e| e.to_string()))
+                .help("Only return events whose message matches this regex. Applied locally after the events are retrieved."),
+        )
+        .arg(
+            Arg::with_name("after-context")
+                .long("after-context")
+                .short("A")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|x| x.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Also print N events after every event that matches --grep and --json-filter. The events around matches can't be known to AWS, so every event in the time range is retrieved, which can be much more data than the matches alone."),
+        )
+        .arg(
+            Arg::with_name("before-context")
+                .long("before-context")
+                .short("B")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|x| x.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Also print N events before every event that matches --grep and --json-filter. Like --after-context, this retrieves every event in the time range."),
+        )
+        .arg(
+            Arg::with_name("context")
+                .long("context")
+                .short("C")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|x| x.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Like --before-context and --after-context with the same N."),
+        )
+        .arg(
+            Arg::with_name("after")
+                .long("after")
+                .takes_value(true)
+                .value_name("time")
+                .help("Only print events at or after this time, in the formats of --start-time. Applied locally, e.g. to narrow down a cached result without querying AWS again."),
+        )
+        .arg(
+            Arg::with_name("before")
+                .long("before")
+                .takes_value(true)
+                .value_name("time")
+                .help("Only print events at or before this time. Applied locally like --after."),
+        )
+        .arg(
+            Arg::with_name("unique")
+                .long("unique")
+                .help("Collapse consecutive events with the same message into the first of them, prefixed with the number of times it occurred, like uniq -c. JSON output gets a count field instead. In follow mode, a message is printed once a different one arrives."),
+        )
+        .arg(
+            Arg::with_name("unique-global")
+                .long("unique-global")
+                .help("Like --unique, but collapse every event with the same message, not only consecutive ones. Nothing is printed until all events are retrieved, in the order their messages first occurred."),
+        )
+        .arg(
+            Arg::with_name("grep-invert")
+                .long("grep-invert")
+                .requires("grep")
+                .help("Only return events whose message doesn't match the --grep regex."),
+        )
+        .arg(
+            Arg::with_name("ignore-case")
+                .long("ignore-case")
+                .short("i")
+                .requires("grep")
+                .help("Match the --grep regex case-insensitively."),
+        )
+        .arg(
+            Arg::with_name("json-filter")
+                .long("json-filter")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|x| JsonFilter::parse(&x).map(|_| ()))
+                .help("Only return events whose message is JSON matching this condition, e.g. '.level == \"ERROR\"', '.status >= 500' or '.user.id' to require a field. Can be repeated, all conditions must match. Applied locally."),
+        )
+        .arg(
+            Arg::with_name("aws-timeout")
+                .long("aws-timeout")
+                .global(true)
+                .takes_value(true)
+                .validator(|x| x.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("The number of seconds to wait for credentials from the instance metadata service and for each API request."),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .long("jobs")
+                .short("j")
+                .takes_value(true)
+                .default_value("1")
+                .validator(|x| match x.parse::<usize>() {
+                    Ok(0) => Err("must be at least 1".to_string()),
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e.to_string()),
+                })
+                .help("Split the time range into this many parts that are fetched concurrently. Only used when both --start-time and --end-time are given without --max-items."),
+        )
+        .arg(
+            Arg::with_name("page-size")
+                .long("page-size")
+                .takes_value(true)
+                .default_value("1000")
+                .validator(|x| match x.parse::<i64>() {
+                    Ok(1..=10000) => Ok(()),
+                    Ok(_) => Err("must be between 1 and 10000".to_string()),
+                    Err(e) => Err(e.to_string()),
+                })
+                .help("The number of events to request per page. Larger pages need fewer requests for big time ranges."),
+        )
+        .arg(
+            Arg::with_name("max-retries")
+                .long("max-retries")
+                .takes_value(true)
+                .default_value("3")
+                .validator(|x| match x.parse::<u32>() {
+                    Ok(0..=10) => Ok(()),
+                    Ok(_) => Err("must be at most 10".to_string()),
+                    Err(e) => Err(e.to_string()),
+                })
+                .help("How often a throttled or failed request is retried, at most 10, with exponential backoff from 0.5 up to 30 seconds."),
+        )
+        .arg(Arg::with_name("filter-pattern").help("The filter pattern to use."))
+        .arg(
+            Arg::with_name("filter-file")
+                .long("filter-file")
+                .takes_value(true)
+                .conflicts_with("filter-pattern")
+                .help("Read the filter pattern from a file, or from stdin when this is -."),
+        )
+        .arg(
+            Arg::with_name("patterns")
+                .long("filter-pattern")
+                .takes_value(true)
+                .value_name("pattern")
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&["filter-pattern", "filter-file"])
+                .help("A filter pattern, which can be given more than once to match events that match any of them. Every pattern is a separate query, run concurrently and merged by timestamp, so AWS scans the time range once per pattern. Events that match several patterns are printed once. Results of more than one pattern are not cached."),
+        )
+        .arg(
+            Arg::with_name("explain-filter")
+                .long("explain-filter")
+                .help("Describe which events the filter pattern matches instead of running the query. Patterns are always checked for mistakes like unbalanced quotes or brackets before they are sent."),
+        )
+        .arg(
+            Arg::with_name("list-groups")
+                .long("list-groups")
+                .takes_value(true)
+                .min_values(0)
+                .value_name("prefix")
+                .help("List the log groups, optionally only those starting with prefix."),
+        )
+        .arg(
+            Arg::with_name("list-streams")
+                .long("list-streams")
+                .help("List the log streams of the log group with their last event time and stored bytes."),
+        )
+        .arg(
+            Arg::with_name("order-by")
+                .long("order-by")
+                .takes_value(true)
+                .possible_values(&["name", "last-event-time"])
+                .requires("list-streams")
+                .help("The order of the listed log streams. Streams ordered by last event time are listed newest first."),
+        )
+        .arg(
+            Arg::with_name("bytes")
+                .long("bytes")
+                .takes_value(true)
+                .global(true)
+                .possible_values(&["human", "raw"])
+                .default_value("human")
+                .help("How sizes are shown by --stats, --list-streams and the cache subcommands: in units like KiB and MiB, or as a number of bytes for scripts."),
+        )
+        .arg(
+            Arg::with_name("cache-dir")
+                .long("cache-dir")
+                .takes_value(true)
+                .global(true)
+                .env("CLOUDWATCH_CACHE_DIR")
+                .help("The directory used to cache results. Defaults to cloudwatch in the user's cache directory, or in the temporary directory when there is none."),
+        )
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("cache")
+                .about("Manages cached results.")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("clear").about("Deletes all cached results."))
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("Lists cached results with their age, size and query."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("insights")
+                .about("Runs a CloudWatch Logs Insights query and prints the results as a table.")
+                .arg(
+                    Arg::with_name("query")
+                        .required(true)
+                        .help("The query, e.g. 'stats count(*) by bin(5m)'. See https://docs.aws.amazon.com/AmazonCloudWatch/latest/logs/CWL_QuerySyntax.html"),
+                )
+                .arg(
+                    Arg::with_name("log-group-name")
+                        .long("log-group")
+                        .short("g")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(true)
+                        .help("The name or ARN of a log group to query. Can be repeated, every group is queried separately. The account of an ARN is ignored, like for the main command."),
+                )
+                .arg(
+                    Arg::with_name("start-time")
+                        .long("start-time")
+                        .alias("since")
+                        .short("S")
+                        .takes_value(true)
+                        .default_value("1h")
+                        .help("The start of the time range, in the same formats as for events."),
+                )
+                .arg(
+                    Arg::with_name("end-time")
+                        .long("end-time")
+                        .alias("until")
+                        .short("U")
+                        .takes_value(true)
+                        .help("The end of the time range. Defaults to now."),
+                )
+                .arg(
+                    Arg::with_name("max-items")
+                        .long("max-items")
+                        .alias("limit")
+                        .short("n")
+                        .takes_value(true)
+                        .validator(|x| match parse_count(&x) {
+                            Ok(1..=10000) => Ok(()),
+                            Ok(_) => Err("must be between 1 and 10000".to_string()),
+                            Err(e) => Err(e),
+                        })
+                        .help("The number of rows each group returns at most. Defaults to 1000."),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .default_value("text")
+                        .help("The format of the output. json prints every row as a JSON object on its own line."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Prints a completion script for the given shell.")
+                .arg(
+                    Arg::with_name("shell")
+                        .required(true)
+                        .possible_values(&Shell::variants()),
+                ),
+        )
+}
+
+fn run() -> Result<(), Error> {
+    let matches = app().get_matches_safe().unwrap_or_else(|e| match e.kind {
+        ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => e.exit(),
+        _ => {
+            eprintln!("{}", e.message);
+            process::exit(EXIT_USAGE);
+        }
+    });
+
+    QUIET.store(matches.is_present("quiet"), Ordering::Relaxed);
+    VERBOSITY.store(
+        matches.occurrences_of("verbose") as usize,
+        Ordering::Relaxed,
+    );
+
+    colored::control::set_override(match matches.value_of("color") {
+        Some("always") => true,
+        Some("never") => false,
+        // The same output goes to --output-file, which shouldn't get colors
+        _ => {
+            env::var_os("NO_COLOR").is_none()
+                && io::stdout().is_terminal()
+                && !matches.is_present("output-file")
+                && !matches.is_present("gzip")
+        }
+    });
+
+    let raw_bytes = matches.value_of("bytes") == Some("raw");
+    let cache_dir = match matches.value_of("cache-dir") {
+        Some(cache_dir) => PathBuf::from(cache_dir),
+        None => dirs::cache_dir()
+            .unwrap_or_else(env::temp_dir)
+            .join(Path::new("cloudwatch")),
+    };
+
+    if let Some(matches) = matches.subcommand_matches("completions") {
+        let shell = match matches.value_of("shell").map(str::parse::<Shell>) {
+            Some(Ok(shell)) => shell,
+            _ => return Err(CloudwatchError::Args("unsupported shell".into()).into()),
+        };
+        app().gen_completions_to("cloudwatch", shell, &mut io::stdout());
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("cache") {
+        if matches.subcommand_matches("clear").is_some() {
+            let (count, bytes) = clear_cache(&cache_dir)?;
+            writeln!(
+                io::stdout(),
+                "Removed {} files, freed {}",
+                count,
+                format_size(bytes, raw_bytes)
+            )?;
+            return Ok(());
+        }
+        if matches.subcommand_matches("list").is_some() {
+            return print_cache(&cache_dir, raw_bytes);
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("insights") {
+        return insights(matches);
+    }
+
+    // The group of an ARN is queried by name, in the region of the ARN
+    let log_group_name = matches.value_of("log-group-name").unwrap_or_default();
+    let arn = LogGroupArn::parse(log_group_name)?;
+    if let Some(arn) = &arn {
+        warn_arn_account(arn);
+    }
+    let log_group_name = arn.as_ref().map_or(log_group_name, |x| x.name);
+    let log_stream_names = matches.values_of("log-stream-name").map(|x| {
+        let mut names: Vec<&str> = x.collect();
+        names.sort();
+        names.dedup();
+        names
+    });
+    let stream_prefix = matches.value_of("stream-prefix");
+    let filter_pattern = match matches.value_of("filter-file") {
+        Some(path) => {
+            let mut pattern = String::new();
+            if path == "-" {
+                io::stdin().read_to_string(&mut pattern)
+            } else {
+                File::open(path).and_then(|mut x| x.read_to_string(&mut pattern))
+            }
+            .map_err(CloudwatchError::io(&format!(
+                "could not read filter pattern from {}",
+                path
+            )))?;
+            Some(pattern.trim().to_string())
+        }
+        None => matches.value_of("filter-pattern").map(|x| x.to_string()),
+    };
+    // Several patterns are separate queries, so the request has none
+    let patterns: Vec<String> = match matches.values_of("patterns") {
+        Some(patterns) => patterns.map(|x| x.to_string()).collect(),
+        None => filter_pattern.into_iter().collect(),
+    };
+    let filter_pattern = match patterns.as_slice() {
+        [pattern] => Some(pattern.clone()),
+        _ => None,
+    };
+    if patterns.len() > 1 {
+        for name in &["follow", "resume-token"] {
+            if matches.is_present(name) {
+                return Err(CloudwatchError::Args(format!(
+                    "--{} can't be used with more than one filter pattern",
+                    name
+                ))
+                .into());
+            }
+        }
+    }
+    // AWS only reports that a pattern is invalid, not why
+    let mut parsed = Vec::new();
+    for pattern in &patterns {
+        parsed.push(
+            FilterPattern::parse(pattern)
+                .map_err(|e| CloudwatchError::Args(format!("invalid filter pattern: {}", e)))?,
+        );
+    }
+    if matches.is_present("explain-filter") {
+        if parsed.is_empty() {
+            return Err(
+                CloudwatchError::Args("--explain-filter requires a filter pattern".into()).into(),
+            );
+        }
+        let explained: Vec<String> = parsed.iter().map(|x| x.explain()).collect();
+        let mut out = io::stdout().lock();
+        if explained.len() > 1 {
+            writeln!(out, "Matches events that match any of these patterns.\n")?;
+        }
+        return Ok(out.write_all(explained.join("\nor\n\n").as_bytes())?);
+    }
+    let start_time = matches.value_of("start-time");
+    let end_time = matches.value_of("end-time");
+    let max_items = parse_value(&matches, "max-items", parse_count)?;
+    let tail = parse_value(&matches, "tail", str::parse::<usize>)?;
+    let max_bytes = parse_value(&matches, "limit-bytes", parse_size)?;
+    let by_ingestion = matches.value_of("sort-by") == Some("ingestion");
+    if by_ingestion && matches.is_present("follow") {
+        return Err(CloudwatchError::Args(
+            "--sort-by ingestion can't be used with --follow".into(),
+        )
+        .into());
+    }
+    if max_bytes.is_some() {
+        for name in &["tail", "reverse"] {
+            if matches.is_present(name) {
+                return Err(CloudwatchError::Args(format!(
+                    "--limit-bytes can't be used with --{}",
+                    name
+                ))
+                .into());
+            }
+        }
+    }
+    if ["before-context", "after-context", "context"]
+        .iter()
+        .any(|x| matches.is_present(x))
+    {
+        if !patterns.is_empty() {
+            return Err(CloudwatchError::Args(
+                "context can't be shown for a filter pattern, which AWS applies; use --grep or --json-filter instead".into(),
+            )
+            .into());
+        }
+        if by_ingestion {
+            return Err(CloudwatchError::Args(
+                "context can't be shown with --sort-by ingestion".into(),
+            )
+            .into());
+        }
+        for name in &["tail", "reverse"] {
+            if matches.is_present(name) {
+                return Err(CloudwatchError::Args(format!(
+                    "context can't be shown with --{}",
+                    name
+                ))
+                .into());
+            }
+        }
+    }
+    if tail.is_some() {
+        for name in &["max-items", "reverse"] {
+            if matches.is_present(name) {
+                return Err(
+                    CloudwatchError::Args(format!("--tail can't be used with --{}", name)).into(),
+                );
+            }
+        }
+    }
+    let output = match matches.value_of("output") {
+        _ if matches.is_present("count") => Output::Count,
+        _ if matches.is_present("text") => Output::Text,
+        Some("text") => Output::Text,
+        _ if matches.is_present("raw") => Output::Raw,
+        Some("csv") => Output::Csv,
+        Some("raw") => Output::Raw,
+        Some("json-array") => Output::JsonArray,
+        None if matches.is_present("template") => Output::Template,
+        Some("template") => Output::Template,
+        _ => Output::Json,
+    };
+    // The events of an envelope are an array in a single JSON object
+    let envelope = matches.is_present("envelope");
+    let output = match output {
+        Output::Json | Output::JsonArray if envelope => Output::JsonArray,
+        _ if envelope => {
+            return Err(CloudwatchError::Args("--envelope requires JSON output".into()).into())
+        }
+        output => output,
+    };
+    for name in &["json-indent", "json-compact"] {
+        if matches.is_present(name) && !matches!(output, Output::Json | Output::JsonArray) {
+            return Err(CloudwatchError::Args(format!("--{} requires JSON output", name)).into());
+        }
+    }
+    for name in &["csv-delim", "no-header", "bom"] {
+        if matches.is_present(name) && output != Output::Csv {
+            return Err(CloudwatchError::Args(format!("--{} requires CSV output", name)).into());
+        }
+    }
+    let unique = match (
+        matches.is_present("unique"),
+        matches.is_present("unique-global"),
+    ) {
+        (_, true) => Some(true),
+        (true, false) => Some(false),
+        (false, false) => None,
+    };
+    if let Some(global) = unique {
+        if output == Output::Csv {
+            return Err(
+                CloudwatchError::Args("--unique can't be used with CSV output".into()).into(),
+            );
+        }
+        for name in &["rate", "before-context", "after-context", "context"] {
+            if matches.is_present(name) {
+                return Err(CloudwatchError::Args(format!(
+                    "--unique can't be used with --{}",
+                    name
+                ))
+                .into());
+            }
+        }
+        if global && matches.is_present("follow") {
+            return Err(CloudwatchError::Args(
+                "--unique-global can't be used with --follow".into(),
+            )
+            .into());
+        }
+    }
+    for name in &["tee", "rotate"] {
+        if matches.is_present(name) && !matches.is_present("output-file") {
+            return Err(CloudwatchError::Args(format!("--{} requires --output-file", name)).into());
+        }
+    }
+    if matches.is_present("gzip") && matches.is_present("output-file") && !matches.is_present("tee")
+    {
+        return Err(CloudwatchError::Args(
+            "--gzip only compresses stdout, which isn't written with --output-file unless --tee is given".into(),
+        )
+        .into());
+    }
+    if matches.is_present("rotate") {
+        if !matches.is_present("follow") {
+            return Err(CloudwatchError::Args("--rotate requires --follow".into()).into());
+        }
+        // Every file would only hold a piece of the array
+        if output == Output::JsonArray {
+            return Err(
+                CloudwatchError::Args("--rotate can't be used with a JSON array".into()).into(),
+            );
+        }
+    }
+    match (output == Output::Template, matches.is_present("template")) {
+        (true, false) => {
+            return Err(
+                CloudwatchError::Args("--output template requires --template".into()).into(),
+            )
+        }
+        (false, true) => {
+            return Err(CloudwatchError::Args(
+                "--template can only be used with --output template".into(),
+            )
+            .into())
+        }
+        _ => {}
+    }
+    let (zone, dialect) = time_settings(&matches)?;
+    let text_options = TextOptions {
+        pretty: matches.is_present("pretty"),
+        time_format: matches.value_of("time-format").map(|x| x.to_string()),
+        zone,
+        template: parse_value(&matches, "template", parse_template)?.unwrap_or_default(),
+        show_ingestion: matches.is_present("show-ingestion"),
+        severity: match matches.values_of("severity-field") {
+            Some(fields) => Some(fields.map(|x| x.to_string()).collect()),
+            None if matches.is_present("severity") => {
+                Some(vec!["level".to_string(), "severity".to_string()])
+            }
+            None => None,
+        },
+        show_stream: if matches.is_present("show-stream") {
+            parse_value(&matches, "stream-width", str::parse)?
+        } else {
+            None
+        },
+        fields: matches
+            .values_of("fields")
+            .map(|x| x.map(|x| x.to_string()).collect()),
+        csv: Csv {
+            delimiter: match matches.value_of("csv-delim") {
+                Some("tab") | Some("\\t") => '\t',
+                Some(x) => x.chars().next().unwrap_or(','),
+                None => ',',
+            },
+            header: !matches.is_present("no-header"),
+            bom: matches.is_present("bom"),
+        },
+        raw_bytes,
+        json_indent: matches.is_present("json-indent"),
+        highlight: if matches.is_present("no-highlight") {
+            Vec::new()
+        } else {
+            patterns.iter().flat_map(|x| filter_terms(x)).collect()
+        },
+    };
+    let filter = EventFilter {
+        grep: parse_value(&matches, "grep", |x| {
+            RegexBuilder::new(x)
+                .case_insensitive(matches.is_present("ignore-case"))
+                .build()
+        })?,
+        invert: matches.is_present("grep-invert"),
+        json: matches
+            .values_of("json-filter")
+            .map(|x| {
+                x.map(|x| {
+                    JsonFilter::parse(x).map_err(|e| {
+                        CloudwatchError::Args(format!(
+                            "invalid value '{}' for --json-filter: {}",
+                            x, e
+                        ))
+                    })
+                })
+                .collect::<Result<_, _>>()
+            })
+            .transpose()?
+            .unwrap_or_default(),
+        // Set once the time that relative times count back from is known
+        after: None,
+        before: None,
+    };
+    let mut printer = Printer {
+        output,
+        writer: RefCell::new(output.writer()),
+        filter,
+        text: text_options,
+        count: Cell::new(0),
+        size: Cell::new(0),
+        span: Cell::new(None),
+        stats: matches.is_present("stats"),
+        exit_on_empty: matches.is_present("exit-on-empty"),
+        strip: if matches.is_present("strip-control") {
+            Some(Regex::new(CONTROL)?)
+        } else {
+            None
+        },
+        unique: unique.map(Unique::new),
+        exclude_stream: parse_value(&matches, "exclude-stream", Regex::new)?,
+        context: match (
+            parse_value(&matches, "before-context", str::parse)?,
+            parse_value(&matches, "after-context", str::parse)?,
+            parse_value(&matches, "context", str::parse)?,
+        ) {
+            (None, None, None) => None,
+            (before, after, both) => Some(Context {
+                before: before.or(both).unwrap_or(0),
+                after: after.or(both).unwrap_or(0),
+                recent: RefCell::new(VecDeque::new()),
+                remaining: Cell::new(0),
+                skipped: Cell::new(false),
+            }),
+        },
+        rate: parse_value(&matches, "rate", parse_duration)?.map(|window| Rate {
+            window: (window.as_millis() as i64).max(1),
+            counts: RefCell::new(BTreeMap::new()),
+            next: Cell::new(None),
+        }),
+        progress: if matches.is_present("progress")
+            && !matches.is_present("quiet")
+            && !matches.is_present("verbose")
+            && io::stderr().is_terminal()
+        {
+            Some(Progress::new())
+        } else {
+            None
+        },
+        held: RefCell::new(match tail {
+            Some(_) => Some(Held {
+                events: VecDeque::new(),
+                capacity: tail,
+                reverse: false,
+                by_ingestion,
+            }),
+            None if matches.is_present("reverse") => Some(Held {
+                events: VecDeque::new(),
+                capacity: max_items.map(|x| x.max(0) as usize),
+                reverse: true,
+                by_ingestion,
+            }),
+            None if by_ingestion => Some(Held {
+                events: VecDeque::new(),
+                capacity: None,
+                reverse: false,
+                by_ingestion,
+            }),
+            None => None,
+        }),
+    };
+    let force = matches.is_present("force");
+    let max_age = parse_value(&matches, "max-age", parse_duration)?;
+    let profile = matches.value_of("profile");
+    let region = match (matches.value_of("region"), &arn) {
+        (Some(region), Some(arn)) if region != arn.region => {
+            return Err(CloudwatchError::Args(format!(
+                "--region {} doesn't match the region of the log group ARN, {}",
+                region, arn.region
+            ))
+            .into())
+        }
+        (None, Some(arn)) => Some(arn.region),
+        (region, _) => region,
+    };
+    let endpoint = matches.value_of("endpoint-url");
+    if matches.is_present("print-config") {
+        return print_config(profile, region, arn.is_some(), endpoint);
+    }
+    let follow = matches.is_present("follow");
+    if matches.is_present("rate") && !follow {
+        return Err(CloudwatchError::Args("--rate requires --follow".into()).into());
+    }
+    // Following needs a range without an end
+    if follow {
+        for name in &["end-time", "for", "reverse", "group-prefix"] {
+            if matches.is_present(name) {
+                return Err(CloudwatchError::Args(format!(
+                    "--follow can't be used with --{}",
+                    name
+                ))
+                .into());
+            }
+        }
+    }
+    let default_range = parse_value(&matches, "default-range", parse_duration)?.unwrap_or_default();
+    let interval = parse_value(&matches, "interval", str::parse)?
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+
+    if let Some(entries) = matches.values_of("merge") {
+        for name in &[
+            "start-time",
+            "end-time",
+            "for",
+            "max-items",
+            "limit-bytes",
+            "follow",
+            "list-groups",
+            "list-streams",
+            "relative-to",
+        ] {
+            if matches.occurrences_of(name) > 0 {
+                return Err(CloudwatchError::Args(format!(
+                    "--merge can't be used with --{}",
+                    name
+                ))
+                .into());
+            }
+        }
+        let mut paths = Vec::new();
+        for entry in entries {
+            paths.push(find_cache_entry(&cache_dir, entry)?);
+        }
+        let now = Utc::now();
+        printer.filter.after = parse_time_arg(&matches, "after", zone, dialect, now)?;
+        printer.filter.before = parse_time_arg(&matches, "before", zone, dialect, now)?;
+        let mut out = open_output(&matches)?;
+        printer.write_header(&mut out, None)?;
+        let skipped = merge_cache(&paths, |event| printer.print(&mut out, &event))?;
+        if skipped > 0 {
+            notice!("warning: skipped {} malformed lines in cache", skipped);
+        }
+        return printer.finish(&mut out, None);
+    }
+
+    let client = connect(&matches, region)?;
+
+    // A workload that stopped logging is easier to investigate with relative
+    // times that count back from its last event
+    let now = if matches.value_of("relative-to") == Some("last-event") {
+        // Resolving the last event would call AWS, which --dry-run promises
+        // not to
+        for name in &[
+            "follow",
+            "offline",
+            "dry-run",
+            "group-prefix",
+            "list-groups",
+            "list-streams",
+        ] {
+            if matches.is_present(name) {
+                return Err(CloudwatchError::Args(format!(
+                    "--relative-to last-event can't be used with --{}",
+                    name
+                ))
+                .into());
+            }
+        }
+        let last = last_event_time(
+            &client,
+            log_group_name,
+            log_stream_names.as_deref(),
+            stream_prefix,
+        )?;
+        match last {
+            Some(last) => {
+                notice!(
+                    "Times are relative to the last event at {}",
+                    zone.format(last, None)
+                );
+                Utc.timestamp_millis(last)
+            }
+            None => {
+                return Err(CloudwatchError::Aws(format!(
+                    "no events found in log group '{}'",
+                    log_group_name
+                ))
+                .into())
+            }
+        }
+    } else {
+        Utc::now()
+    };
+    let to_timestamp = |x| {
+        zone.parse(x, now, dialect)
+            .map_err(|e| CloudwatchError::DateParse(x.to_string(), e))
+    };
+    printer.filter.after = parse_time_arg(&matches, "after", zone, dialect, now)?;
+    printer.filter.before = parse_time_arg(&matches, "before", zone, dialect, now)?;
+    // Relative times like "10m ago" or "9am" resolve differently when parsed
+    // at another moment
+    let is_relative = |x| {
+        let earlier = now - chrono::Duration::days(400);
+        zone.parse(x, now, dialect).ok() != zone.parse(x, earlier, dialect).ok()
+    };
+
+    let offline = matches.is_present("offline");
+    if offline {
+        for name in &["list-groups", "list-streams", "follow", "group-prefix"] {
+            if matches.is_present(name) {
+                return Err(CloudwatchError::Args(format!(
+                    "--offline can't be used with --{}",
+                    name
+                ))
+                .into());
+            }
+        }
+    }
+
+    if matches.is_present("list-groups") {
+        let prefix = matches.value_of("list-groups");
+        let mut out = open_output(&matches)?;
+        list_groups(&client, prefix, printer.output, &mut out)?;
+        return Ok(out.close()?);
+    }
+
+    if matches.is_present("list-streams") {
+        let request = DescribeLogStreamsRequest {
+            descending: Some(matches.value_of("order-by") == Some("last-event-time")),
+            log_group_name: log_group_name.to_string(),
+            log_stream_name_prefix: stream_prefix.map(|x| x.to_string()),
+            order_by: matches.value_of("order-by").map(|x| match x {
+                "last-event-time" => "LastEventTime".to_string(),
+                _ => "LogStreamName".to_string(),
+            }),
+            ..Default::default()
+        };
+        let limit = max_items;
+        let mut out = open_output(&matches)?;
+        list_streams(
+            &client,
+            request,
+            limit,
+            printer.output,
+            &printer.text,
+            &mut out,
+        )?;
+        return Ok(out.close()?);
+    }
+
+    let request = FilterLogEventsRequest {
+        end_time: end_time.map(to_timestamp).transpose()?,
+        filter_pattern,
+        // AWS ignores this since June 2019 and always interleaves the streams,
+        // so there is no option to turn it off. It's still sent because it is
+        // part of the cache key.
+        interleaved: Some(true),
+        log_group_name: log_group_name.to_string(),
+        log_stream_name_prefix: stream_prefix.map(|x| x.to_string()),
+        log_stream_names: log_stream_names
+            .as_ref()
+            .map(|x| x.iter().map(|x| x.to_string()).collect()),
+        start_time: start_time.map(to_timestamp).transpose()?,
+        next_token: match matches.value_of("resume-token-file") {
+            Some(path) => Some(
+                fs::read_to_string(path)
+                    .map_err(CloudwatchError::io(&format!(
+                        "could not read token from {}",
+                        path
+                    )))?
+                    .trim()
+                    .to_string(),
+            ),
+            None => matches.value_of("resume-token").map(|x| x.to_string()),
+        },
+        ..Default::default()
+    };
+    let request = match parse_value(&matches, "for", parse_duration)? {
+        Some(duration) => {
+            let duration = duration.as_millis() as i64;
+            match (request.start_time, request.end_time) {
+                (Some(start), None) => FilterLogEventsRequest {
+                    end_time: Some(start + duration),
+                    ..request
+                },
+                (None, Some(end)) => FilterLogEventsRequest {
+                    start_time: Some(end - duration),
+                    ..request
+                },
+                (Some(_), Some(_)) => {
+                    return Err(CloudwatchError::Args(
+                        "--for can't be used with both --start-time and --end-time".into(),
+                    )
+                    .into())
+                }
+                (None, None) => {
+                    return Err(CloudwatchError::Args(
+                        "--for requires --start-time or --end-time".into(),
+                    )
+                    .into())
+                }
+            }
+        }
+        None => request,
+    };
+    // Scanning from the very first event rarely makes sense when only a
+    // number of lines is requested
+    let request = match (start_time, end_time, max_items, tail) {
+        (None, None, Some(_), None) if !follow => FilterLogEventsRequest {
+            start_time: Some(now.timestamp_millis() - default_range.as_millis() as i64),
+            ..request
+        },
+        (None, None, None, Some(_)) => FilterLogEventsRequest {
+            start_time: Some(now.timestamp_millis() - default_range.as_millis() as i64),
+            ..request
+        },
+        _ => request,
+    };
+    // The actual time, since --relative-to last-event may count back from
+    // the past
+    let now_millis = Utc::now().timestamp_millis();
+    let clamped = !follow
+        && !matches.is_present("allow-future")
+        && request.end_time.is_some_and(|x| x > now_millis);
+    let request = if clamped {
+        if request.start_time.is_some_and(|x| x > now_millis) {
+            return Err(CloudwatchError::Args(format!(
+                "the time range starting {} is entirely in the future; pass --allow-future to query it anyway",
+                format_time(request.start_time.unwrap_or_default(), &printer.text)
+            ))
+            .into());
+        }
+        notice!(
+            "warning: end time {} is in the future, using now instead; pass --allow-future to keep it",
+            format_time(request.end_time.unwrap_or_default(), &printer.text)
+        );
+        FilterLogEventsRequest {
+            end_time: Some(now_millis),
+            ..request
+        }
+    } else {
+        request
+    };
+    let snap = parse_value(&matches, "snap", parse_duration)?.map(|x| x.as_millis() as i64);
+    let request = match snap {
+        // An open range ends now, so that it is closed and can be cached
+        Some(snap) => FilterLogEventsRequest {
+            start_time: request.start_time.map(|x| x - x.rem_euclid(snap)),
+            end_time: match request.end_time {
+                None if follow => None,
+                end => Some(end.unwrap_or(now.timestamp_millis())),
+            }
+            .map(|x| x - x.rem_euclid(snap)),
+            ..request
+        },
+        None => request,
+    };
+    if let (Some(start), Some(end)) = (request.start_time, request.end_time) {
+        if start > end {
+            return Err(CloudwatchError::Args(format!(
+                "start time {} is after end time {}",
+                format_time(start, &printer.text),
+                format_time(end, &printer.text)
+            ))
+            .into());
+        }
+        if start == end {
+            notice!(
+                "warning: the time range only covers the instant {}",
+                format_time(start, &printer.text)
+            );
+        }
+    }
+    if !follow && request.start_time > Some(now_millis) {
+        notice!("warning: the time range is entirely in the future");
+    }
+    // Newest-first output needs the end of the range, so the limit is applied
+    // locally instead
+    let limit = max_items.filter(|_| !matches.is_present("reverse"));
+    let paging = Paging {
+        max_items: limit,
+        max_bytes,
+        page_size: parse_value(&matches, "page-size", str::parse)?.unwrap_or(1000),
+        max_retries: parse_value(&matches, "max-retries", str::parse)?.unwrap_or_default(),
+        jobs: parse_value(&matches, "jobs", str::parse)?.unwrap_or(1),
+        progress: printer.progress.as_ref(),
+    };
+
+    // Open-ended and relative ranges keep changing, so they are never cached
+    // unless --snap holds relative ones still. Neither are ranges moved back
+    // to end now, or results from multiple groups or patterns.
+    let uncached = if matches.is_present("no-cache") {
+        Some("--no-cache")
+    } else if follow {
+        Some("--follow")
+    } else if matches.is_present("group-prefix") {
+        Some("multiple log groups")
+    } else if matches.is_present("exclude-stream") {
+        Some("--exclude-stream")
+    } else if patterns.len() > 1 {
+        Some("multiple filter patterns")
+    } else if request.end_time.is_none() {
+        Some("no end time")
+    } else if clamped {
+        Some("end time moved back to now")
+    } else if snap.is_none()
+        && (start_time.is_some_and(is_relative) || end_time.is_some_and(is_relative))
+    {
+        Some("relative time range")
+    } else {
+        None
+    };
+    let cacheable = uncached.is_none();
+    // The profile and region in effect, so that results of other accounts or
+    // regions are never served from the cache
+    let profile_used = profile_name(profile).0;
+    let region_used = client.region.name().to_string();
+    let hash = cache_key(
+        &request,
+        limit,
+        max_bytes,
+        Some(&profile_used),
+        Some(&region_used),
+        endpoint,
+    )?;
+    let path = cache_dir.join(Path::new(&hash));
+
+    if matches.is_present("dry-run") {
+        eprintln!("request: {}", serde_json::to_string_pretty(&request)?);
+        if let Some(start) = request.start_time {
+            eprintln!("start time: {}", format_time(start, &printer.text));
+        }
+        if let Some(end) = request.end_time {
+            eprintln!("end time: {}", format_time(end, &printer.text));
+        }
+        if let Some(prefix) = matches.value_of("group-prefix") {
+            eprintln!("log groups: {}*", prefix);
+        }
+        if let Some(exclude) = matches.value_of("exclude-stream") {
+            eprintln!("excluded streams: {}", exclude);
+        }
+        if patterns.len() > 1 {
+            eprintln!("filter patterns: {}", serde_json::to_string(&patterns)?);
+        }
+        match limit {
+            Some(limit) => eprintln!("max items: {}", limit),
+            None => eprintln!("max items: none"),
+        }
+        if let Some(max_bytes) = max_bytes {
+            eprintln!("max bytes: {}", max_bytes);
+        }
+        eprintln!("region: {}", client.region.name());
+        let (name, source) = profile_name(profile);
+        eprintln!("profile: {} ({})", name, source);
+        match uncached {
+            None => eprintln!("cache: {}", path.display()),
+            Some(reason) => eprintln!("cache: not cached ({})", reason),
+        }
+        return Ok(());
+    }
+
+    let mut out = open_output(&matches)?;
+    let envelope = if envelope {
+        let mut query = serde_json::to_value(&request)?;
+        if let (Value::Object(query), Some(prefix)) = (&mut query, matches.value_of("group-prefix"))
+        {
+            query.insert("logGroupNamePrefix".into(), prefix.into());
+        }
+        if let (Value::Object(query), [_, _, ..]) = (&mut query, patterns.as_slice()) {
+            query.insert("filterPatterns".into(), patterns.clone().into());
+        }
+        Some(json!({"query": query, "region": client.region.name()}))
+    } else {
+        None
+    };
+    printer.write_header(&mut out, envelope.as_ref())?;
+
+    if follow {
+        let request = FilterLogEventsRequest {
+            start_time: request.start_time.or(Some(now.timestamp_millis())),
+            ..request
+        };
+        follow_events(&client, request, paging, interval, &printer, &mut out)?;
+        return printer.finish(&mut out, None);
+    }
+
+    let save_token = matches.value_of("save-token");
+    let warn_truncated = |truncated: bool, next_token: &Option<String>| -> Result<(), Error> {
+        if let Some(progress) = &printer.progress {
+            progress.clear();
+        }
+        match (truncated, max_bytes) {
+            (false, _) => {}
+            (true, None) => notice!(
+                "warning: output truncated at {} items; more events match. Increase --max-items or narrow the time range.",
+                limit.unwrap_or_default()
+            ),
+            (true, Some(max_bytes)) => notice!(
+                "warning: output truncated at {}{}; more events match. Increase --limit-bytes or narrow the time range.",
+                limit.map_or(String::new(), |x| format!("{} items or ", x)),
+                format_size(max_bytes, printer.text.raw_bytes)
+            ),
+        }
+        if let Some(token) = next_token {
+            notice!("Continue with --resume-token {}", token);
+        }
+        // A missing file means there is nothing left to resume
+        match (save_token, next_token) {
+            (Some(path), Some(token)) => {
+                fs::write(path, token).map_err(CloudwatchError::io("could not save token"))?
+            }
+            (Some(path), None) if Path::new(path).exists() => {
+                fs::remove_file(path).map_err(CloudwatchError::io("could not remove token file"))?
+            }
+            _ => {}
+        }
+        Ok(())
+    };
+
+    // Every group is queried once per pattern
+    let with_patterns = |request: FilterLogEventsRequest| -> Vec<FilterLogEventsRequest> {
+        if patterns.len() <= 1 {
+            return vec![request];
+        }
+        patterns
+            .iter()
+            .map(|x| FilterLogEventsRequest {
+                filter_pattern: Some(x.clone()),
+                ..request.clone()
+            })
+            .collect()
+    };
+
+    // Results from multiple groups are not cached
+    if let Some(prefix) = matches.value_of("group-prefix") {
+        let mut groups = Vec::new();
+        describe_groups(&client, Some(prefix), |group| {
+            groups.extend(group.log_group_name);
+            Ok(())
+        })?;
+        if groups.is_empty() {
+            return Err(
+                CloudwatchError::Aws(format!("no log groups start with '{}'", prefix)).into(),
+            );
+        }
+        let requests = groups
+            .into_iter()
+            .flat_map(|group| {
+                with_patterns(FilterLogEventsRequest {
+                    log_group_name: group,
+                    ..request.clone()
+                })
+            })
+            .collect();
+        let truncated = fetch_merged(&client, requests, true, paging, |event| {
+            printer.print(&mut out, &event)
+        })?;
+        warn_truncated(truncated, &None)?;
+        return printer.finish(&mut out, Some(truncated));
+    }
+
+    if !cacheable && offline {
+        return Err(CloudwatchError::Args(
+            "--offline requires a fixed time range, other queries are never cached".into(),
+        )
+        .into());
+    }
+    if let Some(reason) = uncached {
+        verbose!(1, "not cached: {}", reason);
+        // A resumed query continues the single request that printed the token
+        if let (Some(exclude), None) = (&printer.exclude_stream, &request.next_token) {
+            let mut streams = Vec::new();
+            let mut complete = true;
+            describe_streams(&client, log_group_name, stream_prefix, |stream| {
+                if let Some(name) = stream.log_stream_name {
+                    if !exclude.is_match(&name) {
+                        streams.push(name);
+                    }
+                }
+                complete = streams.len() <= MAX_STREAM_NAMES * MAX_STREAM_REQUESTS;
+                Ok(complete)
+            })?;
+            if streams.is_empty() {
+                notice!("warning: every log stream matches --exclude-stream");
+                return printer.finish(&mut out, Some(false));
+            }
+            if complete {
+                verbose!(1, "querying {} log streams by name", streams.len());
+                let requests = streams
+                    .chunks(MAX_STREAM_NAMES)
+                    .flat_map(|names| {
+                        with_patterns(FilterLogEventsRequest {
+                            log_stream_names: Some(names.to_vec()),
+                            log_stream_name_prefix: None,
+                            ..request.clone()
+                        })
+                    })
+                    .collect();
+                let truncated = fetch_merged(&client, requests, false, paging, |event| {
+                    printer.print(&mut out, &event)
+                })?;
+                warn_truncated(truncated, &None)?;
+                return printer.finish(&mut out, Some(truncated));
+            }
+            verbose!(
+                1,
+                "more than {} log streams left, dropping excluded events locally",
+                MAX_STREAM_NAMES * MAX_STREAM_REQUESTS
+            );
+        }
+        if patterns.len() > 1 {
+            let truncated =
+                fetch_merged(&client, with_patterns(request), false, paging, |event| {
+                    printer.print(&mut out, &event)
+                })?;
+            warn_truncated(truncated, &None)?;
+            return printer.finish(&mut out, Some(truncated));
+        }
+        let next_token = fetch_events(&client, request, paging, |event| {
+            printer.print(&mut out, &event)
+        })?;
+        warn_truncated(next_token.is_some(), &next_token)?;
+        return printer.finish(&mut out, Some(next_token.is_some()));
+    }
+
+    fs::create_dir_all(&cache_dir).map_err(CloudwatchError::cache("could not create cache dir"))?;
+
+    let expired = |path: &Path| match max_age {
+        Some(max_age) => fs::metadata(path)
+            .and_then(|x| x.modified())
+            .map(|x| x.elapsed().is_ok_and(|x| x > max_age))
+            .unwrap_or(true),
+        None => false,
+    };
+
+    // Check cache first
+    // Expired results are better than none when AWS can't be reached
+    let cached = if !force && path.exists() && (offline || !expired(&path)) {
+        open_cache(&path).map_err(CloudwatchError::cache("could not read cache"))?
+    } else {
+        None
+    };
+    verbose!(
+        1,
+        "cache {}: {}",
+        match &cached {
+            Some(_) => "hit",
+            None if !path.exists() => "miss",
+            None if force => "skipped for --force",
+            None if offline || !expired(&path) => "in another format",
+            None => "expired",
+        },
+        path.display()
+    );
+    if let Some(mut file) = cached {
+        if printer.is_passthrough() {
+            // Lines are counted to tell whether the result is empty
+            let mut buffer = [0; 64 * 1024];
+            let mut lines = 0;
+            loop {
+                let read = file
+                    .read(&mut buffer)
+                    .map_err(CloudwatchError::cache("could not read cache"))?;
+                if read == 0 {
+                    break;
+                }
+                lines += buffer[..read].iter().filter(|&&x| x == b'\n').count() as u64;
+                out.write_all(&buffer[..read])?;
+            }
+            printer.count.set(lines);
+        } else {
+            // A partially written or corrupt cache shouldn't abort the output
+            let mut skipped = 0;
+            for line in file.split(b'\n') {
+                // Invalid UTF-8 in messages is replaced rather than dropping
+                // the event
+                let event = match line {
+                    Ok(line) => serde_json::from_str(&String::from_utf8_lossy(&line)).ok(),
+                    Err(e) if e.kind() == io::ErrorKind::InvalidData => None,
+                    Err(e) => {
+                        return Err(CloudwatchError::Cache("could not read cache".into(), e).into())
+                    }
+                };
+                match event {
+                    Some(event) => printer.print(&mut out, &event)?,
+                    None => skipped += 1,
+                }
+            }
+            if skipped > 0 {
+                notice!("warning: skipped {} malformed lines in cache", skipped);
+            }
+        }
+        return printer.finish(&mut out, None);
+    }
+
+    if offline {
+        return Err(CloudwatchError::NotCached.into());
+    }
+
+    let meta = CacheMeta {
+        request: serde_json::to_value(&request)?,
+        limit,
+        profile: Some(profile_used),
+        region: Some(region_used),
+        endpoint: endpoint.map(|x| x.to_string()),
+    };
+    // Overwrites any partial file left behind by a process that was killed
+    let mut file = PartialFile::create(
+        path.with_extension("partial"),
+        !matches.is_present("no-compress"),
+    )
+    .map_err(CloudwatchError::cache("could not write cache"))?;
+
+    let next_token = fetch_events(&client, request, paging, |event| {
+        let json = serde_json::to_string(&event)?;
+        let bytes = json.as_bytes();
+        file.write_all(bytes)
+            .and_then(|_| file.write_all(NEWLINE))
+            .map_err(CloudwatchError::cache("could not write cache"))?;
+        // The cache contains all events, local filters are applied on read
+        if printer.is_passthrough() {
+            out.write_all(bytes)?;
+            out.write_all(NEWLINE)?;
+            printer.record(&event);
+        } else {
+            printer.print(&mut out, &event)?;
+        }
+        Ok(())
+    })?;
+
+    file.persist(&path)
+        .map_err(CloudwatchError::cache("could not write cache"))?;
+    meta.write(&path)
+        .map_err(CloudwatchError::cache("could not write cache"))?;
+    warn_truncated(next_token.is_some(), &next_token)?;
+    printer.finish(&mut out, Some(next_token.is_some()))
+}
+
+/// Returns the cache file for a key, the start of a key or the path of a file.
+fn find_cache_entry(cache_dir: &Path, entry: &str) -> Result<PathBuf, Error> {
+    if Path::new(entry).is_file() {
+        return Ok(PathBuf::from(entry));
+    }
+    let matching: Vec<PathBuf> = list_cache(cache_dir)?
+        .into_iter()
+        .map(|x| x.path)
+        .filter(|x| {
+            x.file_name()
+                .is_some_and(|x| x.to_string_lossy().starts_with(entry))
+        })
+        .collect();
+    match matching.as_slice() {
+        [path] => Ok(path.clone()),
+        [] => Err(CloudwatchError::Args(format!("no cache entry '{}'", entry)).into()),
+        _ => Err(CloudwatchError::Args(format!(
+            "'{}' is the start of {} cache keys, give more of it",
+            entry,
+            matching.len()
+        ))
+        .into()),
+    }
+}
+
+/// Formats a size for people unless `raw`.
+fn format_size(bytes: u64, raw: bool) -> String {
+    if raw {
+        bytes.to_string()
+    } else {
+        format_bytes(bytes)
+    }
+}
+
+/// Prints a table of the cache entries and the queries that produced them.
+fn print_cache(cache_dir: &Path, raw_bytes: bool) -> Result<(), Error> {
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let time = |x: i64| Zone::Local.format(x, None);
+    writeln!(
+        out,
+        "{:29}  {:>10}  {:12}  QUERY",
+        "MODIFIED", "SIZE", "KEY"
+    )?;
+    for entry in list_cache(cache_dir)? {
+        let modified = entry
+            .modified
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_millis() as i64)
+            .unwrap_or_default();
+        let key: String = entry
+            .path
+            .file_name()
+            .map(|x| x.to_string_lossy().chars().take(12).collect())
+            .unwrap_or_default();
+        let query = match entry.meta {
+            Some(meta) => {
+                let request = &meta.request;
+                let mut query = request["logGroupName"].as_str().unwrap_or("").to_string();
+                if let Some(start) = request["startTime"].as_i64() {
+                    query += &format!(" from {}", time(start));
+                }
+                if let Some(end) = request["endTime"].as_i64() {
+                    query += &format!(" to {}", time(end));
+                }
+                if let Some(pattern) = request["filterPattern"].as_str() {
+                    query += &format!(" matching {:?}", pattern);
+                }
+                if let Some(limit) = meta.limit {
+                    query += &format!(", at most {}", limit);
+                }
+                if let Some(region) = &meta.region {
+                    query += &format!(" in {}", region);
+                }
+                query
+            }
+            None => "unknown".to_string(),
+        };
+        writeln!(
+            out,
+            "{:29}  {:>10}  {:12}  {}",
+            time(modified),
+            format_size(entry.size, raw_bytes),
+            key,
+            query
+        )?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Parses the value of an argument. Most are validated by clap already, but a
+/// failure here is still reported as a usage error rather than a panic.
+fn parse_value<T, E, F>(matches: &ArgMatches, name: &str, parse: F) -> Result<Option<T>, Error>
+where
+    E: fmt::Display,
+    F: FnOnce(&str) -> Result<T, E>,
+{
+    match matches.value_of(name) {
+        Some(value) => parse(value).map(Some).map_err(|e| {
+            CloudwatchError::Args(format!("invalid value '{}' for --{}: {}", value, name, e)).into()
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Picks the date dialect from the locale, US for en_US and UK otherwise.
+fn locale_dialect() -> Dialect {
+    let locale = ["LC_ALL", "LC_TIME", "LANG"]
+        .iter()
+        .filter_map(|x| env::var(x).ok())
+        .find(|x| !x.is_empty());
+    match locale {
+        Some(locale) if locale.starts_with("en_US") => Dialect::Us,
+        _ => Dialect::Uk,
+    }
+}
+
+/// Returns the time zone and dialect that times are parsed in.
+fn time_settings(matches: &ArgMatches) -> Result<(Zone, Dialect), Error> {
+    let zone = match parse_value(matches, "timezone", str::parse::<Tz>)? {
+        Some(tz) => Zone::Named(tz),
+        None => Zone::Local,
+    };
+    let dialect = match matches.value_of("dialect") {
+        Some("us") => Dialect::Us,
+        Some(_) => Dialect::Uk,
+        None => locale_dialect(),
+    };
+    Ok((zone, dialect))
+}
+
+/// Where results are written: stdout, --output-file or with --tee both. With
+/// --pager, stdout is replaced by a pager when the first output is written,
+/// so an empty result doesn't start one.
+struct Sink {
+    stdout: Option<Box<dyn Write>>,
+    /// Takes the place of stdout with --gzip, until closed.
+    gzip: Option<GzEncoder<BufWriter<io::StdoutLock<'static>>>>,
+    file: Option<OutputFile>,
+    /// The command of the pager still to start.
+    pager: Option<String>,
+    child: Option<process::Child>,
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(command) = self.pager.take() {
+            let mut child = start_pager(&command)?;
+            if let Some(input) = child.stdin.take() {
+                self.stdout = Some(Box::new(BufWriter::new(input)));
+            }
+            self.child = Some(child);
+        }
+        if let Some(stdout) = &mut self.stdout {
+            stdout.write_all(buf)?;
+        }
+        if let Some(gzip) = &mut self.gzip {
+            gzip.write_all(buf)?;
+        }
+        if let Some(file) = &mut self.file {
+            file.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(stdout) = &mut self.stdout {
+            stdout.flush()?;
+        }
+        if let Some(gzip) = &mut self.gzip {
+            gzip.flush()?;
+        }
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Output that has to be completed once everything is written.
+trait Close: Write {
+    /// Completes the output and flushes it.
+    fn close(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl Close for Vec<u8> {}
+
+impl Close for Sink {
+    /// Writes the end of a --gzip stream, which a failed write would leave
+    /// truncated.
+    fn close(&mut self) -> io::Result<()> {
+        if let Some(mut gzip) = self.gzip.take() {
+            gzip.try_finish()?;
+            gzip.get_mut().flush()?;
+        }
+        self.flush()
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        // Closing its input lets the pager reach the end, then it runs until
+        // the user quits it
+        self.stdout.take();
+        if let Some(mut child) = self.child.take() {
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Starts a pager like `less -R`, with its input piped from this process.
+fn start_pager(command: &str) -> io::Result<process::Child> {
+    let mut words = command.split_whitespace();
+    process::Command::new(words.next().unwrap_or("less"))
+        .args(words)
+        // Keeps colors, and like git, exits at once when the output fits on
+        // the screen
+        .env(
+            "LESS",
+            env::var("LESS").unwrap_or_else(|_| "FRX".to_string()),
+        )
+        .stdin(process::Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("could not start pager '{}': {}", command, e),
+            )
+        })
+}
+
+/// A file that is rotated once it holds `rotate` bytes: `path` is renamed to
+/// `path.1` after moving the existing `path.1` to `path.2` and so on, so
+/// higher numbers are older. Rotation waits for the end of a line, so events
+/// are never split across files.
+struct OutputFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    written: u64,
+    rotate: Option<u64>,
+    line_start: bool,
+}
+
+impl OutputFile {
+    fn create(path: PathBuf, rotate: Option<u64>) -> io::Result<OutputFile> {
+        Ok(OutputFile {
+            writer: BufWriter::new(File::create(&path)?),
+            path,
+            written: 0,
+            rotate,
+            line_start: true,
+        })
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let mut last = 1;
+        while self.rotated(last).exists() {
+            last += 1;
+        }
+        for n in (1..last).rev() {
+            fs::rename(self.rotated(n), self.rotated(n + 1))?;
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        self.writer = BufWriter::new(File::create(&self.path)?);
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.line_start && self.rotate.is_some_and(|x| self.written >= x) {
+            self.rotate()?;
+        }
+        self.writer.write_all(buf)?;
+        self.written += buf.len() as u64;
+        self.line_start = buf.ends_with(NEWLINE);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Opens the output selected by --output-file, --tee, --rotate, --pager and
+/// --gzip.
+fn open_output(matches: &ArgMatches) -> Result<Sink, Error> {
+    let file = match matches.value_of("output-file") {
+        Some(path) => Some(
+            OutputFile::create(
+                PathBuf::from(path),
+                parse_value(matches, "rotate", parse_size)?,
+            )
+            .map_err(|e| CloudwatchError::Io(format!("could not create {}", path), e))?,
+        ),
+        None => None,
+    };
+    let stdout = file.is_none() || matches.is_present("tee");
+    let gzip = stdout && matches.is_present("gzip");
+    let pager = if stdout && matches.is_present("pager") && io::stdout().is_terminal() {
+        Some(
+            env::var("PAGER")
+                .ok()
+                .filter(|x| !x.trim().is_empty())
+                .unwrap_or_else(|| "less -R".to_string()),
+        )
+    } else {
+        None
+    };
+    Ok(Sink {
+        stdout: if stdout && pager.is_none() && !gzip {
+            Some(Box::new(BufWriter::new(io::stdout().lock())))
+        } else {
+            None
+        },
+        gzip: if gzip {
+            Some(GzEncoder::new(
+                BufWriter::new(io::stdout().lock()),
+                Compression::default(),
+            ))
+        } else {
+            None
+        },
+        file,
+        pager,
+        child: None,
+    })
+}
+
+/// Parses the time given for the argument `name`, if any, relative to `now`.
+fn parse_time_arg(
+    matches: &ArgMatches,
+    name: &str,
+    zone: Zone,
+    dialect: Dialect,
+    now: DateTime<Utc>,
+) -> Result<Option<i64>, Error> {
+    match matches.value_of(name) {
+        Some(x) => {
+            Ok(Some(zone.parse(x, now, dialect).map_err(|e| {
+                CloudwatchError::DateParse(x.to_string(), e)
+            })?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Prints the settings that `connect` would use for --print-config.
+fn print_config(
+    profile: Option<&str>,
+    region: Option<&str>,
+    from_arn: bool,
+    endpoint: Option<&str>,
+) -> Result<(), Error> {
+    let region = match region {
+        Some(region) if from_arn => format!("{} (log group ARN)", region),
+        Some(region) => format!("{} (--region)", region),
+        None => {
+            let (region, source) = default_region(profile)?;
+            format!("{} ({})", region.name(), source)
+        }
+    };
+    let (name, source) = profile_name(profile);
+    let mut out = io::stdout().lock();
+    writeln!(out, "profile: {} ({})", name, source)?;
+    writeln!(out, "region: {}", region)?;
+    writeln!(out, "credentials: {}", credentials_source(profile))?;
+    if let Some(endpoint) = endpoint {
+        writeln!(out, "endpoint: {}", endpoint)?;
+    }
+    Ok(())
+}
+
+/// Creates a client for the profile, endpoint and timeout of the arguments, in
+/// `region` when given.
+fn connect(matches: &ArgMatches, region: Option<&str>) -> Result<Client, Error> {
+    let timeout = parse_value(matches, "aws-timeout", str::parse)?.map(Duration::from_secs);
+    create_client(
+        matches.value_of("profile"),
+        region
+            .map(|x| x.parse::<Region>())
+            .transpose()
+            .map_err(|e| CloudwatchError::Args(e.to_string()))?,
+        matches.value_of("endpoint-url"),
+        timeout,
+    )
+}
+
+/// Warns that a log group ARN is queried by name, since requests can't name
+/// the group of another account.
+fn warn_arn_account(arn: &LogGroupArn) {
+    notice!(
+        "warning: the account {} of the ARN is ignored, {} is queried in the account of the credentials",
+        arn.account,
+        arn.name
+    );
+}
+
+/// Runs the Logs Insights query of the `insights` subcommand.
+fn insights(matches: &ArgMatches) -> Result<(), Error> {
+    let mut groups = Vec::new();
+    let mut region = matches.value_of("region");
+    for group in matches.values_of("log-group-name").unwrap_or_default() {
+        match LogGroupArn::parse(group)? {
+            Some(arn) => {
+                warn_arn_account(&arn);
+                match region {
+                    Some(region) if region != arn.region => {
+                        return Err(CloudwatchError::Args(format!(
+                            "the log group ARNs and --region must all be in the same region, not {} and {}",
+                            region, arn.region
+                        ))
+                        .into())
+                    }
+                    _ => region = Some(arn.region),
+                }
+                groups.push(arn.name.to_string());
+            }
+            None => groups.push(group.to_string()),
+        }
+    }
+
+    let (zone, dialect) = time_settings(matches)?;
+    let now = Utc::now();
+    let to_timestamp = |x: &str| {
+        zone.parse(x, now, dialect)
+            .map_err(|e| CloudwatchError::DateParse(x.to_string(), e))
+    };
+    // Insights queries take times in seconds
+    let start_time = to_timestamp(matches.value_of("start-time").unwrap_or_default())? / 1000;
+    let end_time = match matches.value_of("end-time") {
+        Some(end_time) => to_timestamp(end_time)?,
+        None => now.timestamp_millis(),
+    } / 1000;
+    if start_time > end_time {
+        return Err(CloudwatchError::Args("the start of the range is after the end".into()).into());
+    }
+
+    let client = connect(matches, region)?;
+    let request = StartQueryRequest {
+        query_string: matches.value_of("query").unwrap_or_default().to_string(),
+        start_time,
+        end_time,
+        limit: parse_value(matches, "max-items", parse_count)?,
+        ..Default::default()
+    };
+    let rows = run_query(&client, request, &groups, Duration::from_secs(1))?;
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    if matches.value_of("output") == Some("json") {
+        // Written by hand to keep the fields in the order of the query
+        for row in rows {
+            let fields: Vec<String> = row
+                .iter()
+                .map(|(field, value)| format!("{}:{}", json!(field), json!(value)))
+                .collect();
+            writeln!(out, "{{{}}}", fields.join(","))?;
+        }
+    } else {
+        print_table(&mut out, &rows)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Prints rows of query results as a table, with a column for every field in
+/// the order they first appear.
+fn print_table<W: Write>(out: &mut W, rows: &[QueryRow]) -> io::Result<()> {
+    let mut columns: Vec<(&str, usize)> = Vec::new();
+    for (field, value) in rows.iter().flatten() {
+        let width = value.chars().count();
+        match columns.iter_mut().find(|(x, _)| x == field) {
+            Some(column) => column.1 = column.1.max(width),
+            None => columns.push((field, width.max(field.chars().count()))),
+        }
+    }
+    let mut write_row = |values: Vec<&str>| {
+        let mut line = String::new();
+        for (i, (value, (_, width))) in values.iter().zip(&columns).enumerate() {
+            if i + 1 < columns.len() {
+                line += &format!("{:width$}  ", value, width = width);
+            } else {
+                line += value;
+            }
+        }
+        writeln!(out, "{}", line.trim_end())
+    };
+    write_row(columns.iter().map(|(field, _)| *field).collect())?;
+    for row in rows {
+        let value = |field: &str| {
+            row.iter()
+                .find(|(x, _)| x == field)
+                .map_or("", |(_, value)| value.as_str())
+        };
+        write_row(columns.iter().map(|(field, _)| value(field)).collect())?;
+    }
+    Ok(())
+}
+
+/// Prints the names of all log groups starting with `prefix`, or their
+/// details when the output is JSON.
+fn list_groups<W: Write>(
+    client: &Client,
+    prefix: Option<&str>,
+    output: Output,
+    out: &mut W,
+) -> Result<(), Error> {
+    describe_groups(client, prefix, |group| {
+        if matches!(output, Output::Json | Output::JsonArray) {
+            let group = LogGroup {
+                log_group_name: group.log_group_name,
+                creation_time: group.creation_time,
+                retention_in_days: group.retention_in_days,
+                stored_bytes: group.stored_bytes,
+            };
+            serde_json::to_writer(&mut *out, &group)?;
+            out.write_all(NEWLINE)?;
+        } else if let Some(name) = group.log_group_name {
+            writeln!(out, "{}", name)?;
+        }
+        Ok(())
+    })?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Prints the log streams matching `request`, at most `max_items` of them.
+fn list_streams<W: Write>(
+    client: &Client,
+    mut request: DescribeLogStreamsRequest,
+    max_items: Option<i64>,
+    output: Output,
+    options: &TextOptions,
+    out: &mut W,
+) -> Result<(), Error> {
+    let mut remaining = max_items;
+    while remaining.is_none() || remaining.unwrap() > 0 {
+        request.limit = Some(remaining.unwrap_or(50).min(50));
+        let response = client
+            .logs
+            .describe_log_streams(request.clone())
+            .sync()
+            .map_err(|e| match e {
+                RusotoError::Service(DescribeLogStreamsError::ResourceNotFound(_)) => {
+                    group_not_found(client, &request.log_group_name)
+                }
+                e => aws_error(e),
+            })?;
+        let streams = response.log_streams.unwrap_or_default();
+        if let Some(count) = remaining {
+            remaining = Some(count - streams.len() as i64);
+        }
+        for stream in streams {
+            let stream = LogStream {
+                log_stream_name: stream.log_stream_name,
+                creation_time: stream.creation_time,
+                first_event_timestamp: stream.first_event_timestamp,
+                last_event_timestamp: stream.last_event_timestamp,
+                stored_bytes: stream.stored_bytes,
+            };
+            if matches!(output, Output::Json | Output::JsonArray) {
+                serde_json::to_writer(&mut *out, &stream)?;
+                out.write_all(NEWLINE)?;
+            } else {
+                let last_event = stream
+                    .last_event_timestamp
+                    .map(|x| format_time(x, options))
+                    .unwrap_or_else(|| "-".to_string());
+                writeln!(
+                    out,
+                    "{}\t{}\t{}",
+                    stream.log_stream_name.unwrap_or_default(),
+                    last_event,
+                    format_size(
+                        stream.stored_bytes.unwrap_or_default().max(0) as u64,
+                        options.raw_bytes
+                    )
+                )?;
+            }
+        }
+        request.next_token = response.next_token;
+        if request.next_token.is_none() {
+            break;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Prints all events matching `request` and then keeps polling for new ones
+/// every `interval` until interrupted with Ctrl-C.
+fn follow_events<W: Write>(
+    client: &Client,
+    mut request: FilterLogEventsRequest,
+    mut paging: Paging,
+    interval: Duration,
+    printer: &Printer,
+    out: &mut W,
+) -> Result<(), Error> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    {
+        let interrupted = interrupted.clone();
+        ctrlc::set_handler(move || interrupted.store(true, Ordering::SeqCst)).map_err(|e| {
+            CloudwatchError::Io("could not set Ctrl-C handler".into(), io::Error::other(e))
+        })?;
+    }
+
+    // Event ids returned by the previous poll, used to drop duplicates when
+    // windows overlap.
+    let mut seen = HashSet::new();
+    let mut last_timestamp = None;
+    let start = request.start_time.unwrap_or_default();
+
+    while !interrupted.load(Ordering::SeqCst) {
+        let polled = Utc::now().timestamp_millis();
+        let mut current = HashSet::new();
+        fetch_events(client, request.clone(), paging, |event| {
+            if let Some(id) = &event.event_id {
+                if seen.contains(id) {
+                    return Ok(());
+                }
+                current.insert(id.clone());
+            }
+            last_timestamp = event.timestamp.max(last_timestamp);
+            printer.print(out, &event)?;
+            Ok(())
+        })?;
+        // Only the initial fetch is trimmed by --tail
+        printer.release(out)?;
+        // Later events of a window would start after the last one seen, so
+        // windows are complete once polled past their end
+        printer.print_rates(out, start, polled)?;
+        out.flush()?;
+
+        if !current.is_empty() {
+            seen = current;
+        }
+        if let Some(timestamp) = last_timestamp {
+            request.start_time = Some(timestamp + 1);
+        }
+        // Only the initial fetch is limited
+        paging.max_items = None;
+        paging.max_bytes = None;
+
+        let deadline = Instant::now() + interval;
+        while !interrupted.load(Ordering::SeqCst) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    Ok(())
+}
+
+/// Formats events for one kind of output. The printer decides which events
+/// are written and when; writers only decide how.
+trait OutputWriter {
+    /// Writes the start of the output. The fields of an `envelope` are written
+    /// before the events, and completed by `finish`.
+    fn write_header(
+        &mut self,
+        _out: &mut dyn Write,
+        _options: &TextOptions,
+        _envelope: Option<&Value>,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn write_event(
+        &mut self,
+        out: &mut dyn Write,
+        event: &LogEvent,
+        options: &TextOptions,
+    ) -> Result<(), Error>;
+
+    /// Writes the first event of a message collapsed by --unique, with the
+    /// number of times it occurred in front of it.
+    fn write_counted(
+        &mut self,
+        out: &mut dyn Write,
+        event: &LogEvent,
+        count: u64,
+        options: &TextOptions,
+    ) -> Result<(), Error> {
+        write!(out, "{:>7} ", count)?;
+        self.write_event(out, event, options)
+    }
+
+    /// Writes whatever is needed to complete the output. `truncated` tells
+    /// whether events were left out because of `--max-items`, when known.
+    fn finish(&mut self, _out: &mut dyn Write, _truncated: Option<bool>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Output {
+    /// Creates the writer for this format.
+    fn writer(self) -> Box<dyn OutputWriter> {
+        match self {
+            Output::Json | Output::JsonArray => Box::new(JsonWriter {
+                array: self == Output::JsonArray,
+                envelope: false,
+                count: 0,
+            }),
+            Output::Text => Box::new(TextWriter),
+            Output::Csv => Box::new(CsvWriter),
+            Output::Raw => Box::new(RawWriter),
+            Output::Template => Box::new(TemplateWriter),
+            Output::Count => Box::new(CountWriter { count: 0 }),
+        }
+    }
+}
+
+/// Writes one JSON object per line, or a single array streamed as events
+/// arrive.
+struct JsonWriter {
+    array: bool,
+    /// Whether the array is part of an --envelope object, which is completed
+    /// with the count and truncation.
+    envelope: bool,
+    count: u64,
+}
+
+impl OutputWriter for JsonWriter {
+    fn write_header(
+        &mut self,
+        out: &mut dyn Write,
+        _options: &TextOptions,
+        envelope: Option<&Value>,
+    ) -> Result<(), Error> {
+        if !self.array {
+            return Ok(());
+        }
+        match envelope {
+            Some(Value::Object(envelope)) => {
+                // Leaves the object open for the events
+                let fields = serde_json::to_string(envelope)?;
+                write!(out, "{},\"events\":[", &fields[..fields.len() - 1])?;
+                self.envelope = true;
+            }
+            _ => out.write_all(b"[")?,
+        }
+        Ok(())
+    }
+
+    fn write_event(
+        &mut self,
+        out: &mut dyn Write,
+        event: &LogEvent,
+        options: &TextOptions,
+    ) -> Result<(), Error> {
+        self.write_object(out, event, None, options)
+    }
+
+    fn write_counted(
+        &mut self,
+        out: &mut dyn Write,
+        event: &LogEvent,
+        count: u64,
+        options: &TextOptions,
+    ) -> Result<(), Error> {
+        self.write_object(out, event, Some(count), options)
+    }
+
+    fn finish(&mut self, out: &mut dyn Write, truncated: Option<bool>) -> Result<(), Error> {
+        if !self.array {
+            return Ok(());
+        }
+        out.write_all(if self.count == 0 { b"]" } else { b"\n]" })?;
+        if self.envelope {
+            writeln!(
+                out,
+                ",\"count\":{},\"truncated\":{}}}",
+                self.count,
+                serde_json::to_string(&truncated)?
+            )?;
+        } else {
+            out.write_all(NEWLINE)?;
+        }
+        Ok(())
+    }
+}
+
+impl JsonWriter {
+    /// Writes an event, with a count field for --unique.
+    fn write_object(
+        &mut self,
+        out: &mut dyn Write,
+        event: &LogEvent,
+        count: Option<u64>,
+        options: &TextOptions,
+    ) -> Result<(), Error> {
+        if self.array {
+            out.write_all(if self.count == 0 { b"\n" } else { b",\n" })?;
+        } else if options.json_indent && self.count > 0 {
+            // Objects spanning several lines are told apart by a blank line
+            out.write_all(NEWLINE)?;
+        }
+        let object = match (&options.fields, options.json_indent) {
+            (Some(fields), true) => serde_json::to_string_pretty(&select_fields(event, fields)?)?,
+            (Some(fields), false) => serde_json::to_string(&select_fields(event, fields)?)?,
+            (None, true) => serde_json::to_string_pretty(event)?,
+            (None, false) => serde_json::to_string(event)?,
+        };
+        match count {
+            // Appended, so the fields keep their order
+            Some(count) => {
+                let (separator, field, end) = if options.json_indent {
+                    (",\n  ", format!("\"count\": {}", count), "\n}")
+                } else {
+                    (",", format!("\"count\":{}", count), "}")
+                };
+                let fields = object[1..object.len() - 1].trim_end();
+                if fields.is_empty() {
+                    let start = if options.json_indent { "{\n  " } else { "{" };
+                    write!(out, "{}{}{}", start, field, end)?;
+                } else {
+                    write!(out, "{{{}{}{}{}", fields, separator, field, end)?;
+                }
+            }
+            None => out.write_all(object.as_bytes())?,
+        }
+        if !self.array {
+            out.write_all(NEWLINE)?;
+        }
+        self.count += 1;
+        Ok(())
+    }
+}
+
+/// Writes a line per event with the time and message, in color.
+struct TextWriter;
+
+impl OutputWriter for TextWriter {
+    fn write_event(
+        &mut self,
+        out: &mut dyn Write,
+        event: &LogEvent,
+        options: &TextOptions,
+    ) -> Result<(), Error> {
+        Ok(print_event(out, event, options)?)
+    }
+}
+
+/// Writes a row per event with the time, stream and message, or --fields.
+struct CsvWriter;
+
+impl OutputWriter for CsvWriter {
+    fn write_header(
+        &mut self,
+        out: &mut dyn Write,
+        options: &TextOptions,
+        _envelope: Option<&Value>,
+    ) -> Result<(), Error> {
+        if options.csv.bom {
+            out.write_all("\u{feff}".as_bytes())?;
+        }
+        if !options.csv.header {
+            return Ok(());
+        }
+        let delimiter = options.csv.delimiter.to_string();
+        match &options.fields {
+            Some(fields) => writeln!(out, "{}", fields.join(&delimiter))?,
+            None => writeln!(
+                out,
+                "{}",
+                ["timestamp", "logStreamName", "message"].join(&delimiter)
+            )?,
+        }
+        Ok(())
+    }
+
+    fn write_event(
+        &mut self,
+        out: &mut dyn Write,
+        event: &LogEvent,
+        options: &TextOptions,
+    ) -> Result<(), Error> {
+        let time =
+            |x: Option<i64>| csv_field(&x.map(|x| format_time(x, options)).unwrap_or_default());
+        let row: Vec<String> = match &options.fields {
+            Some(fields) => fields
+                .iter()
+                .map(|field| match field.as_str() {
+                    "logGroupName" => csv_field(event.log_group_name.as_deref().unwrap_or("")),
+                    "eventId" => csv_field(event.event_id.as_deref().unwrap_or("")),
+                    "ingestionTime" => time(event.ingestion_time),
+                    "logStreamName" => csv_field(event.log_stream_name.as_deref().unwrap_or("")),
+                    "message" => csv_field(event.message.as_deref().unwrap_or("")),
+                    _ => time(event.timestamp),
+                })
+                .collect(),
+            None => vec![
+                time(event.timestamp),
+                csv_field(event.log_stream_name.as_deref().unwrap_or("")),
+                csv_field(event.message.as_deref().unwrap_or("")),
+            ],
+        };
+        writeln!(out, "{}", row.join(&options.csv.delimiter.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Writes only the message of every event.
+struct RawWriter;
+
+impl OutputWriter for RawWriter {
+    fn write_event(
+        &mut self,
+        out: &mut dyn Write,
+        event: &LogEvent,
+        _options: &TextOptions,
+    ) -> Result<(), Error> {
+        out.write_all(event.message.as_deref().unwrap_or("").as_bytes())?;
+        out.write_all(NEWLINE)?;
+        Ok(())
+    }
+}
+
+/// Writes every event with --template.
+struct TemplateWriter;
+
+impl OutputWriter for TemplateWriter {
+    fn write_event(
+        &mut self,
+        out: &mut dyn Write,
+        event: &LogEvent,
+        options: &TextOptions,
+    ) -> Result<(), Error> {
+        let mut line = String::new();
+        for part in &options.template {
+            match part {
+                TemplatePart::Text(text) => line.push_str(text),
+                TemplatePart::Field(field) => line.push_str(&match field.as_str() {
+                    "time" => event
+                        .timestamp
+                        .map(|x| format_time(x, options))
+                        .unwrap_or_default(),
+                    "timestamp" => event.timestamp.map(|x| x.to_string()).unwrap_or_default(),
+                    "group" => event.log_group_name.clone().unwrap_or_default(),
+                    "stream" => event.log_stream_name.clone().unwrap_or_default(),
+                    "message" => event.message.clone().unwrap_or_default(),
+                    "eventId" => event.event_id.clone().unwrap_or_default(),
+                    _ => event
+                        .ingestion_time
+                        .map(|x| x.to_string())
+                        .unwrap_or_default(),
+                }),
+            }
+        }
+        writeln!(out, "{}", line)?;
+        Ok(())
+    }
+}
+
+/// Writes only the number of events, when finished.
+struct CountWriter {
+    count: u64,
+}
+
+impl OutputWriter for CountWriter {
+    fn write_event(
+        &mut self,
+        _out: &mut dyn Write,
+        _event: &LogEvent,
+        _options: &TextOptions,
+    ) -> Result<(), Error> {
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Counts the distinct messages.
+    fn write_counted(
+        &mut self,
+        _out: &mut dyn Write,
+        _event: &LogEvent,
+        _count: u64,
+        _options: &TextOptions,
+    ) -> Result<(), Error> {
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut dyn Write, _truncated: Option<bool>) -> Result<(), Error> {
+        writeln!(out, "{}", self.count)?;
+        Ok(())
+    }
+}
+
+/// Returns the event with only the given fields.
+fn select_fields(event: &LogEvent, fields: &[String]) -> Result<Value, Error> {
+    let mut value = serde_json::to_value(event)?;
+    let mut selected = serde_json::Map::new();
+    for field in fields {
+        if let Some(x) = value.get_mut(field) {
+            selected.insert(field.clone(), x.take());
+        }
+    }
+    Ok(Value::Object(selected))
+}
+
+/// Quotes a CSV field, doubling any embedded quotes.
+fn csv_field(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+fn print_event<W: Write + ?Sized>(
+    out: &mut W,
+    event: &LogEvent,
+    options: &TextOptions,
+) -> io::Result<()> {
+    let color = match &options.severity {
+        Some(fields) => severity_color(event.message.as_deref().unwrap_or(""), fields),
+        None => None,
+    };
+    match color {
+        Some(color) => {
+            let mut line = Vec::new();
+            write_event_line(&mut line, event, options)?;
+            // The line takes a single color, so drop the styles inside it
+            let line = String::from_utf8_lossy(&line);
+            let mut plain = String::with_capacity(line.len());
+            let mut rest = &*line;
+            while let Some(start) = rest.find("\x1b[") {
+                plain.push_str(&rest[..start]);
+                rest = &rest[start..];
+                rest = match rest.find('m') {
+                    Some(end) => &rest[end + 1..],
+                    None => "",
+                };
+            }
+            plain.push_str(rest);
+            writeln!(out, "{}", plain.trim_end_matches('\n').color(color))
+        }
+        None => write_event_line(out, event, options),
+    }
+}
+
+fn write_event_line<W: Write + ?Sized>(
+    out: &mut W,
+    event: &LogEvent,
+    options: &TextOptions,
+) -> io::Result<()> {
+    let time = match event.timestamp {
+        Some(timestamp) => format_time(timestamp, options),
+        None => "<no timestamp>".to_string(),
+    };
+    let message = event.message.clone().unwrap_or_default();
+    let message = if options.pretty {
+        match serde_json::from_str(&message) {
+            Ok(value @ Value::Object(_)) | Ok(value @ Value::Array(_)) => {
+                let mut pretty = String::new();
+                write_pretty(&value, 0, &mut pretty);
+                pretty
+            }
+            _ => highlight(&message, &options.highlight),
+        }
+    } else {
+        highlight(&message, &options.highlight)
+    };
+    if let Some(group) = &event.log_group_name {
+        write!(out, "{} ", group.cyan())?;
+    }
+    if let Some(width) = options.show_stream {
+        let stream = event.log_stream_name.as_deref().unwrap_or("");
+        let length = stream.chars().count();
+        // The end of a stream name usually identifies the instance
+        let stream = if width > 0 && length > width {
+            let skip = length - width + 1;
+            format!("…{}", stream.chars().skip(skip).collect::<String>())
+        } else {
+            format!("{:width$}", stream, width = width)
+        };
+        write!(out, "{} ", stream.dimmed())?;
+    }
+    write!(out, "{} ", time.green())?;
+    if options.show_ingestion {
+        match (event.timestamp, event.ingestion_time) {
+            (Some(timestamp), Some(ingested)) => {
+                let delay = ingested - timestamp;
+                let delay = if delay.abs() >= 60_000 {
+                    format_delay(delay).red()
+                } else {
+                    format_delay(delay).normal()
+                };
+                write!(
+                    out,
+                    "{} {} ",
+                    format_time(ingested, options).dimmed(),
+                    delay
+                )?;
+            }
+            _ => write!(out, "{} ", "<no ingestion time>".dimmed())?,
+        }
+    }
+    writeln!(out, "{}", message)
+}
+
+/// Returns the color for the severity of a message, read from the first of
+/// `fields` that a JSON message has or from keywords like ERROR or WARN.
+fn severity_color(message: &str, fields: &[String]) -> Option<Color> {
+    let color = |level: &str| match level.to_uppercase().as_str() {
+        "ERROR" | "ERR" | "FATAL" | "CRITICAL" | "CRIT" | "ALERT" | "EMERGENCY" | "PANIC" => {
+            Some(Color::Red)
+        }
+        "WARN" | "WARNING" => Some(Color::Yellow),
+        _ => None,
+    };
+    if let Ok(Value::Object(map)) = serde_json::from_str(message) {
+        if let Some(level) = fields.iter().find_map(|x| map.get(x)) {
+            return level.as_str().and_then(color);
+        }
+    }
+    message
+        .split(|x: char| !x.is_ascii_alphabetic())
+        .filter(|x| x.len() > 2 && x.chars().all(|x| x.is_ascii_uppercase()))
+        .find(|x| color(x).is_some() || ["INFO", "DEBUG", "TRACE", "NOTICE"].contains(x))
+        .and_then(color)
+}
+
+/// Formats the time between an event and its ingestion like +250ms, +1.5s or
+/// +2m05s.
+fn format_delay(millis: i64) -> String {
+    let sign = if millis < 0 { "-" } else { "+" };
+    let millis = millis.abs();
+    if millis < 1000 {
+        format!("{}{}ms", sign, millis)
+    } else if millis < 60_000 {
+        format!("{}{:.1}s", sign, millis as f64 / 1000.0)
+    } else {
+        format!("{}{}m{:02}s", sign, millis / 60_000, millis % 60_000 / 1000)
+    }
+}
+
+/// A filter pattern as AWS interprets it, to catch mistakes before sending it
+/// and for --explain-filter.
+#[derive(Debug, PartialEq)]
+enum FilterPattern {
+    /// Events must contain all `required` terms, one of the `optional` terms
+    /// marked with `?`, and none of the `excluded` terms marked with `-`.
+    Terms {
+        required: Vec<String>,
+        optional: Vec<String>,
+        excluded: Vec<String>,
+    },
+    /// A condition on the fields of JSON messages, like `{ $.level = "ERROR" }`.
+    Json(Condition),
+    /// The names of the fields of messages split on spaces, like
+    /// `[ip, user, status = 404]`, with the conditions on them.
+    SpaceDelimited(Vec<String>, Vec<Condition>),
+}
+
+/// A condition of a JSON or space-delimited pattern.
+#[derive(Debug, PartialEq)]
+enum Condition {
+    /// A field, an operator like `=` or `IS`, and a value like `"ERROR"` or
+    /// `NULL`.
+    Compare(String, String, String),
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+}
+
+/// A token of a JSON or space-delimited pattern.
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Open(char),
+    Close(char),
+    Comma,
+    And,
+    Or,
+    Operator(&'static str),
+    /// A selector, field name, number or unquoted value.
+    Word(String),
+    /// A quoted string, including the quotes.
+    Quoted(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Open(x) | Token::Close(x) => write!(f, "{}", x),
+            Token::Comma => f.write_str(","),
+            Token::And => f.write_str("&&"),
+            Token::Or => f.write_str("||"),
+            Token::Operator(x) => f.write_str(x),
+            Token::Word(x) | Token::Quoted(x) => f.write_str(x),
+        }
+    }
+}
+
+/// Splits a JSON or space-delimited pattern into tokens, checking that quotes
+/// and brackets are balanced.
+fn tokenize(pattern: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut open = Vec::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '"' => {
+                let mut quoted = String::from('"');
+                loop {
+                    match chars.next() {
+                        Some('\\') => {
+                            quoted.push('\\');
+                            quoted.extend(chars.next());
+                        }
+                        Some('"') => break,
+                        Some(c) => quoted.push(c),
+                        None => return Err(format!("{} is never closed", quoted)),
+                    }
+                }
+                quoted.push('"');
+                Token::Quoted(quoted)
+            }
+            '{' | '[' | '(' => {
+                open.push(c);
+                Token::Open(c)
+            }
+            '}' | ']' | ')' => {
+                let expected = match open.pop() {
+                    Some('{') => '}',
+                    Some('[') => ']',
+                    Some(_) => ')',
+                    None => return Err(format!("'{}' closes nothing", c)),
+                };
+                if c != expected {
+                    return Err(format!("expected '{}' instead of '{}'", expected, c));
+                }
+                Token::Close(c)
+            }
+            ',' => Token::Comma,
+            '&' | '|' => {
+                if chars.peek() != Some(&c) {
+                    return Err(format!("'{}' needs to be doubled, '{}{}'", c, c, c));
+                }
+                chars.next();
+                if c == '&' {
+                    Token::And
+                } else {
+                    Token::Or
+                }
+            }
+            '=' if chars.peek() == Some(&'=') => {
+                return Err("'==' isn't an operator, use '=' instead".into())
+            }
+            '=' => Token::Operator("="),
+            '!' | '<' | '>' => {
+                if chars.peek() == Some(&'=') {
+                    chars.next();
+                    Token::Operator(match c {
+                        '!' => "!=",
+                        '<' => "<=",
+                        _ => ">=",
+                    })
+                } else if c == '!' {
+                    return Err("'!' should be '!='".into());
+                } else if c == '<' {
+                    Token::Operator("<")
+                } else {
+                    Token::Operator(">")
+                }
+            }
+            // A regular expression
+            '%' => {
+                let mut regex = String::from('%');
+                loop {
+                    match chars.next() {
+                        Some('%') => break,
+                        Some(c) => regex.push(c),
+                        None => return Err(format!("{} is never closed", regex)),
+                    }
+                }
+                regex.push('%');
+                Token::Word(regex)
+            }
+            c => {
+                let mut word = String::from(c);
+                // Selectors like $.items[0].id include brackets
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "{}()\",&|=!<>".contains(c) {
+                        break;
+                    }
+                    if "[]".contains(c) && !word.starts_with('$') {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                Token::Word(word)
+            }
+        };
+        tokens.push(token);
+    }
+    match open.last() {
+        Some(c) => Err(format!("'{}' is never closed", c)),
+        None => Ok(tokens),
+    }
+}
+
+/// Reads the conditions of a pattern from its tokens.
+struct PatternParser {
+    tokens: Vec<Token>,
+    position: usize,
+    /// Fields are selectors like `$.level` instead of names.
+    json: bool,
+}
+
+impl PatternParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &Token) -> Result<(), String> {
+        match self.next() {
+            Some(ref x) if x == token => Ok(()),
+            Some(x) => Err(format!("expected '{}' instead of '{}'", token, x)),
+            None => Err(format!("expected '{}' at the end", token)),
+        }
+    }
+
+    fn any(&mut self) -> Result<Condition, String> {
+        let mut conditions = vec![self.all()?];
+        while self.eat(&Token::Or) {
+            conditions.push(self.all()?);
+        }
+        Ok(match conditions.len() {
+            1 => conditions.remove(0),
+            _ => Condition::Any(conditions),
+        })
+    }
+
+    fn all(&mut self) -> Result<Condition, String> {
+        let mut conditions = vec![self.comparison()?];
+        while self.eat(&Token::And) {
+            conditions.push(self.comparison()?);
+        }
+        Ok(match conditions.len() {
+            1 => conditions.remove(0),
+            _ => Condition::All(conditions),
+        })
+    }
+
+    fn comparison(&mut self) -> Result<Condition, String> {
+        let field = match self.next() {
+            Some(Token::Open('(')) => {
+                let condition = self.any()?;
+                self.expect(&Token::Close(')'))?;
+                return Ok(condition);
+            }
+            Some(Token::Word(x)) if !self.json || x.starts_with('$') => x,
+            Some(x) if self.json => {
+                return Err(format!(
+                    "expected a selector like $.level instead of '{}'",
+                    x
+                ))
+            }
+            Some(x) => return Err(format!("expected a field name instead of '{}'", x)),
+            None => return Err("expected a condition at the end".into()),
+        };
+        let (operator, value) = match self.next() {
+            Some(Token::Operator(operator)) => (operator.to_string(), self.value()?),
+            Some(Token::Word(x)) if self.json && x.eq_ignore_ascii_case("IS") => {
+                match self.next() {
+                    Some(Token::Word(value))
+                        if ["NULL", "TRUE", "FALSE"]
+                            .iter()
+                            .any(|x| value.eq_ignore_ascii_case(x)) =>
+                    {
+                        ("IS".to_string(), value.to_uppercase())
+                    }
+                    _ => return Err(format!("expected NULL, TRUE or FALSE after {} IS", field)),
+                }
+            }
+            Some(Token::Word(x)) if self.json && x.eq_ignore_ascii_case("NOT") => {
+                match self.next() {
+                    Some(Token::Word(value)) if value.eq_ignore_ascii_case("EXISTS") => {
+                        ("NOT".to_string(), "EXISTS".to_string())
+                    }
+                    _ => return Err(format!("expected EXISTS after {} NOT", field)),
+                }
+            }
+            Some(x) => {
+                return Err(format!(
+                    "expected =, !=, <, <=, > or >= after {} instead of '{}'",
+                    field, x
+                ))
+            }
+            None => return Err(format!("expected =, !=, <, <=, > or >= after {}", field)),
+        };
+        Ok(Condition::Compare(field, operator, value))
+    }
+
+    fn value(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Word(x)) | Some(Token::Quoted(x)) => Ok(x),
+            Some(x) => Err(format!("expected a value instead of '{}'", x)),
+            None => Err("expected a value at the end".into()),
+        }
+    }
+}
+
+impl FilterPattern {
+    fn parse(pattern: &str) -> Result<FilterPattern, String> {
+        let pattern = pattern.trim();
+        if pattern.starts_with('{') {
+            let mut parser = PatternParser {
+                tokens: tokenize(pattern)?,
+                position: 0,
+                json: true,
+            };
+            parser.expect(&Token::Open('{'))?;
+            let condition = parser.any()?;
+            parser.expect(&Token::Close('}'))?;
+            if let Some(x) = parser.next() {
+                return Err(format!("unexpected '{}' after the closing '}}'", x));
+            }
+            return Ok(FilterPattern::Json(condition));
+        }
+        if pattern.starts_with('[') {
+            let mut parser = PatternParser {
+                tokens: tokenize(pattern)?,
+                position: 0,
+                json: false,
+            };
+            parser.expect(&Token::Open('['))?;
+            let mut fields = Vec::new();
+            let mut conditions = Vec::new();
+            while !parser.eat(&Token::Close(']')) {
+                let start = parser.position;
+                match parser.next() {
+                    Some(Token::Word(x)) => fields.push(x),
+                    Some(x) => return Err(format!("expected a field name instead of '{}'", x)),
+                    None => return Err("expected ']' at the end".into()),
+                }
+                if let Some(Token::Operator(_)) | Some(Token::And) | Some(Token::Or) = parser.peek()
+                {
+                    parser.position = start;
+                    conditions.push(parser.any()?);
+                }
+                match parser.peek() {
+                    Some(Token::Comma) => parser.position += 1,
+                    Some(Token::Close(']')) => {}
+                    Some(x) => return Err(format!("expected ',' or ']' instead of '{}'", x)),
+                    None => return Err("expected ']' at the end".into()),
+                }
+            }
+            if let Some(x) = parser.next() {
+                return Err(format!("unexpected '{}' after the closing ']'", x));
+            }
+            return Ok(FilterPattern::SpaceDelimited(fields, conditions));
+        }
+
+        let mut terms = Vec::new();
+        let mut term = String::new();
+        let mut quoted = false;
+        for c in pattern.chars() {
+            match c {
+                '"' => {
+                    quoted = !quoted;
+                    term.push(c);
+                }
+                c if c.is_whitespace() && !quoted => terms.push(std::mem::take(&mut term)),
+                c => term.push(c),
+            }
+        }
+        if quoted {
+            return Err("a '\"' is never closed".into());
+        }
+        terms.push(term);
+        let (mut required, mut optional, mut excluded) = (Vec::new(), Vec::new(), Vec::new());
+        for term in terms.into_iter().filter(|x| !x.is_empty()) {
+            match term.chars().next() {
+                Some('?') => optional.push(term[1..].to_string()),
+                Some('-') => excluded.push(term[1..].to_string()),
+                _ => required.push(term),
+            }
+        }
+        Ok(FilterPattern::Terms {
+            required,
+            optional,
+            excluded,
+        })
+    }
+
+    /// Describes which events match, for --explain-filter.
+    fn explain(&self) -> String {
+        fn describe(condition: &Condition, indent: usize, out: &mut String) {
+            let pad = "  ".repeat(indent);
+            match condition {
+                Condition::Compare(field, operator, value) => {
+                    out.push_str(&format!("{}{} {} {}\n", pad, field, operator, value))
+                }
+                Condition::All(conditions) | Condition::Any(conditions) => {
+                    let all = matches!(condition, Condition::All(_));
+                    out.push_str(&format!("{}{} of:\n", pad, if all { "all" } else { "any" }));
+                    for condition in conditions {
+                        describe(condition, indent + 1, out);
+                    }
+                }
+            }
+        }
+        let quote = |terms: &[String]| {
+            terms
+                .iter()
+                .map(|x| {
+                    if x.starts_with('"') {
+                        x.clone()
+                    } else {
+                        format!("\"{}\"", x)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut out = String::new();
+        match self {
+            FilterPattern::Terms {
+                required,
+                optional,
+                excluded,
+            } => {
+                if required.is_empty() && optional.is_empty() && excluded.is_empty() {
+                    return "Matches all events.\n".to_string();
+                }
+                out.push_str("Term pattern, matches events that:\n");
+                for term in required {
+                    out.push_str(&format!(
+                        "  contain {}\n",
+                        quote(std::slice::from_ref(term))
+                    ));
+                }
+                if !optional.is_empty() {
+                    out.push_str(&format!("  contain any of {}\n", quote(optional)));
+                }
+                if !excluded.is_empty() {
+                    out.push_str(&format!("  contain none of {}\n", quote(excluded)));
+                }
+            }
+            FilterPattern::Json(condition) => {
+                out.push_str("JSON pattern, matches events with a JSON message where:\n");
+                describe(condition, 1, &mut out);
+            }
+            FilterPattern::SpaceDelimited(fields, conditions) => {
+                out.push_str(&format!(
+                    "Space-delimited pattern with {} fields: {}\n",
+                    fields.len(),
+                    fields.join(", ")
+                ));
+                if conditions.is_empty() {
+                    out.push_str("Matches every event with these fields.\n");
+                } else {
+                    out.push_str("Matches events where:\n");
+                    for condition in conditions {
+                        describe(condition, 1, &mut out);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Extracts the terms that an event must contain to match a filter pattern.
+/// JSON and space-delimited patterns don't match literal terms, so they have
+/// none.
+fn filter_terms(pattern: &str) -> Vec<String> {
+    let pattern = pattern.trim();
+    if pattern.starts_with('{') || pattern.starts_with('[') {
+        return Vec::new();
+    }
+    let mut terms = Vec::new();
+    let mut term = String::new();
+    let mut quoted = false;
+    for c in pattern.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ' ' if !quoted => terms.push(std::mem::take(&mut term)),
+            c => term.push(c),
+        }
+    }
+    terms.push(term);
+    terms
+        .into_iter()
+        // Excluded terms never appear in matching events
+        .filter(|x| !x.is_empty() && !x.starts_with('-'))
+        .map(|x| x.trim_start_matches('?').to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+/// Highlights all occurrences of `terms` in `message`. Overlapping matches
+/// are merged into a single highlight.
+fn highlight(message: &str, terms: &[String]) -> String {
+    let mut ranges: Vec<(usize, usize)> = terms
+        .iter()
+        .flat_map(|term| {
+            message
+                .match_indices(term.as_str())
+                .map(move |(i, _)| (i, i + term.len()))
+        })
+        .collect();
+    if ranges.is_empty() {
+        return message.to_string();
+    }
+    ranges.sort();
+
+    let mut result = String::new();
+    let mut position = 0;
+    let mut ranges = ranges.into_iter();
+    let mut current = ranges.next();
+    while let Some((start, mut end)) = current {
+        current = ranges.next();
+        while let Some((next_start, next_end)) = current {
+            if next_start > end {
+                break;
+            }
+            end = end.max(next_end);
+            current = ranges.next();
+        }
+        result.push_str(&message[position..start]);
+        result.push_str(&message[start..end].bright_yellow().to_string());
+        position = end;
+    }
+    result.push_str(&message[position..]);
+    result
+}
+
+/// Formats a timestamp in millis using the configured time format.
+fn format_time(timestamp: i64, options: &TextOptions) -> String {
+    options
+        .zone
+        .format(timestamp, options.time_format.as_deref())
+}
+
+/// Writes an indented JSON value with colored object keys.
+fn write_pretty(value: &Value, indent: usize, out: &mut String) {
+    let pad = |n| "  ".repeat(n);
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            out.push_str("{\n");
+            for (i, (key, value)) in map.iter().enumerate() {
+                let key = serde_json::to_string(key).unwrap();
+                out.push_str(&format!("{}{}: ", pad(indent + 1), key.blue()));
+                write_pretty(value, indent + 1, out);
+                out.push_str(if i + 1 < map.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&format!("{}}}", pad(indent)));
+        }
+        Value::Array(values) if !values.is_empty() => {
+            out.push_str("[\n");
+            for (i, value) in values.iter().enumerate() {
+                out.push_str(&pad(indent + 1));
+                write_pretty(value, indent + 1, out);
+                out.push_str(if i + 1 < values.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&format!("{}]", pad(indent)));
+        }
+        value => out.push_str(&value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> LogEvent {
+        LogEvent {
+            log_group_name: None,
+            event_id: Some("1".to_string()),
+            ingestion_time: Some(1_500_000_000_500),
+            log_stream_name: Some("stream".to_string()),
+            message: Some("hello, \"world\"".to_string()),
+            timestamp: Some(1_500_000_000_000),
+        }
+    }
+
+    fn options() -> TextOptions {
+        TextOptions {
+            pretty: false,
+            time_format: None,
+            highlight: Vec::new(),
+            zone: Zone::Named(chrono_tz::UTC),
+            show_stream: None,
+            show_ingestion: false,
+            severity: None,
+            template: Vec::new(),
+            fields: None,
+            csv: Csv {
+                delimiter: ',',
+                header: true,
+                bom: false,
+            },
+            raw_bytes: false,
+            json_indent: false,
+        }
+    }
+
+    fn render(event: &LogEvent, output: Output, options: &TextOptions) -> String {
+        colored::control::set_override(false);
+        let mut out = Vec::new();
+        output
+            .writer()
+            .write_event(&mut out, event, options)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn json_output() {
+        assert_eq!(
+            render(&event(), Output::Json, &options()),
+            "{\"eventId\":\"1\",\"ingestionTime\":1500000000500,\"logStreamName\":\"stream\",\
+             \"message\":\"hello, \\\"world\\\"\",\"timestamp\":1500000000000}\n"
+        );
+
+        let options = TextOptions {
+            fields: Some(vec!["timestamp".to_string(), "message".to_string()]),
+            ..options()
+        };
+        assert_eq!(
+            render(&event(), Output::Json, &options),
+            "{\"message\":\"hello, \\\"world\\\"\",\"timestamp\":1500000000000}\n"
+        );
+    }
+
+    #[test]
+    fn json_output_indents() {
+        let indented = TextOptions {
+            fields: Some(vec!["timestamp".to_string()]),
+            json_indent: true,
+            ..options()
+        };
+        let mut writer = Output::Json.writer();
+        let mut out = Vec::new();
+        writer.write_event(&mut out, &event(), &indented).unwrap();
+        writer
+            .write_counted(&mut out, &event(), 2, &indented)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\n  \"timestamp\": 1500000000000\n}\n\n\
+             {\n  \"timestamp\": 1500000000000,\n  \"count\": 2\n}\n"
+        );
+    }
+
+    #[test]
+    fn text_output() {
+        assert_eq!(
+            render(&event(), Output::Text, &options()),
+            "2017-07-14T02:40:00.000+00:00 hello, \"world\"\n"
+        );
+
+        let event = LogEvent {
+            log_group_name: Some("group".to_string()),
+            timestamp: None,
+            ..event()
+        };
+        let options = TextOptions {
+            show_stream: Some(4),
+            ..options()
+        };
+        assert_eq!(
+            render(&event, Output::Text, &options),
+            "group …eam <no timestamp> hello, \"world\"\n"
+        );
+    }
+
+    #[test]
+    fn text_output_shows_ingestion() {
+        let options = TextOptions {
+            show_ingestion: true,
+            ..options()
+        };
+        assert_eq!(
+            render(&event(), Output::Text, &options),
+            "2017-07-14T02:40:00.000+00:00 2017-07-14T02:40:00.500+00:00 +500ms hello, \"world\"\n"
+        );
+        assert_eq!(format_delay(1_500), "+1.5s");
+        assert_eq!(format_delay(-125_000), "-2m05s");
+    }
+
+    #[test]
+    fn detects_severity() {
+        let fields = ["level".to_string(), "severity".to_string()];
+        let severity = |x| severity_color(x, &fields);
+        assert_eq!(severity("2024-01-01 ERROR failed"), Some(Color::Red));
+        assert_eq!(severity("[WARN] slow"), Some(Color::Yellow));
+        assert_eq!(severity("INFO warning: error count is 0"), None);
+        assert_eq!(severity("INFO retrying after ERROR"), None);
+        assert_eq!(severity("{\"severity\":\"warning\"}"), Some(Color::Yellow));
+        // The field takes precedence over keywords in other fields
+        assert_eq!(severity("{\"level\":\"info\",\"msg\":\"ERROR\"}"), None);
+        assert_eq!(
+            severity_color("{\"lvl\":\"fatal\"}", &["lvl".to_string()]),
+            Some(Color::Red)
+        );
+
+        let options = TextOptions {
+            severity: Some(fields.to_vec()),
+            ..options()
+        };
+        let event = LogEvent {
+            message: Some("ERROR failed".to_string()),
+            ..event()
+        };
+        assert_eq!(
+            render(&event, Output::Text, &options),
+            "2017-07-14T02:40:00.000+00:00 ERROR failed\n"
+        );
+    }
+
+    #[test]
+    fn text_output_pretty_prints_json() {
+        let event = LogEvent {
+            message: Some("{\"a\":[1]}".to_string()),
+            ..event()
+        };
+        let options = TextOptions {
+            pretty: true,
+            ..options()
+        };
+        assert_eq!(
+            render(&event, Output::Text, &options),
+            "2017-07-14T02:40:00.000+00:00 {\n  \"a\": [\n    1\n  ]\n}\n"
+        );
+    }
+
+    fn printer(output: Output) -> Printer {
+        colored::control::set_override(false);
+        Printer {
+            output,
+            writer: RefCell::new(output.writer()),
+            filter: EventFilter {
+                grep: None,
+                invert: false,
+                json: Vec::new(),
+                after: None,
+                before: None,
+            },
+            text: options(),
+            count: Cell::new(0),
+            size: Cell::new(0),
+            held: RefCell::new(None),
+            span: Cell::new(None),
+            stats: false,
+            exit_on_empty: false,
+            rate: None,
+            context: None,
+            strip: None,
+            exclude_stream: None,
+            unique: None,
+            progress: None,
+        }
+    }
+
+    fn print_held(events: &[LogEvent], held: Held) -> String {
+        let printer = Printer {
+            filter: EventFilter {
+                grep: Some(Regex::new("keep").unwrap()),
+                invert: false,
+                json: Vec::new(),
+                after: None,
+                before: None,
+            },
+            held: RefCell::new(Some(held)),
+            ..printer(Output::Raw)
+        };
+        let mut out = Vec::new();
+        for event in events {
+            printer.print(&mut out, event).unwrap();
+        }
+        assert!(out.is_empty());
+        printer.finish(&mut out, None).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn tail_and_reverse_keep_the_last_matches() {
+        let events: Vec<LogEvent> = ["keep 1", "keep 2", "keep 3", "drop 4"]
+            .iter()
+            .map(|x| LogEvent {
+                message: Some(x.to_string()),
+                ..event()
+            })
+            .collect();
+        let held = |reverse| Held {
+            events: VecDeque::new(),
+            capacity: Some(2),
+            reverse,
+            by_ingestion: false,
+        };
+        assert_eq!(print_held(&events, held(false)), "keep 2\nkeep 3\n");
+        assert_eq!(print_held(&events, held(true)), "keep 3\nkeep 2\n");
+    }
+
+    #[test]
+    fn sorts_by_ingestion_time() {
+        let events: Vec<LogEvent> = [
+            ("keep 1", 30),
+            ("keep 2", 10),
+            ("keep 3", 30),
+            ("keep 4", 20),
+        ]
+        .iter()
+        .map(|(message, ingested)| LogEvent {
+            message: Some(message.to_string()),
+            ingestion_time: Some(*ingested),
+            ..event()
+        })
+        .collect();
+        let held = |capacity, reverse| Held {
+            events: VecDeque::new(),
+            capacity,
+            reverse,
+            by_ingestion: true,
+        };
+        assert_eq!(
+            print_held(&events, held(None, false)),
+            "keep 2\nkeep 4\nkeep 1\nkeep 3\n"
+        );
+        assert_eq!(
+            print_held(&events, held(None, true)),
+            "keep 3\nkeep 1\nkeep 4\nkeep 2\n"
+        );
+        assert_eq!(
+            print_held(&events, held(Some(2), false)),
+            "keep 4\nkeep 3\n"
+        );
+    }
+
+    #[test]
+    fn context_surrounds_matches() {
+        let print = |before, after, messages: &[&str]| {
+            let printer = Printer {
+                filter: EventFilter {
+                    grep: Some(Regex::new("match").unwrap()),
+                    invert: false,
+                    json: Vec::new(),
+                    after: None,
+                    before: None,
+                },
+                context: Some(Context {
+                    before,
+                    after,
+                    recent: RefCell::new(VecDeque::new()),
+                    remaining: Cell::new(0),
+                    skipped: Cell::new(false),
+                }),
+                ..printer(Output::Raw)
+            };
+            let mut out = Vec::new();
+            for message in messages {
+                let event = LogEvent {
+                    message: Some(message.to_string()),
+                    ..event()
+                };
+                printer.print(&mut out, &event).unwrap();
+            }
+            printer.finish(&mut out, None).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let messages = [
+            "1", "2", "match 3", "4", "5", "6", "match 7", "match 8", "9",
+        ];
+        assert_eq!(
+            print(1, 1, &messages),
+            "2\nmatch 3\n4\n--\n6\nmatch 7\nmatch 8\n9\n"
+        );
+        assert_eq!(
+            print(0, 2, &messages),
+            "match 3\n4\n5\n--\nmatch 7\nmatch 8\n9\n"
+        );
+        assert_eq!(print(3, 0, &messages[..4]), "1\n2\nmatch 3\n");
+    }
+
+    #[test]
+    fn rate_counts_events_per_window() {
+        let printer = Printer {
+            rate: Some(Rate {
+                window: 60_000,
+                counts: RefCell::new(BTreeMap::new()),
+                next: Cell::new(None),
+            }),
+            ..printer(Output::Text)
+        };
+        let mut out = Vec::new();
+        let start = 1_500_000_000_000;
+        for &offset in &[10_000, 20_000, 130_000] {
+            let event = LogEvent {
+                timestamp: Some(start + offset),
+                ..event()
+            };
+            printer.print(&mut out, &event).unwrap();
+        }
+        printer
+            .print_rates(&mut out, start, start + 150_000)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "2017-07-14T02:40:00.000+00:00 2\n2017-07-14T02:41:00.000+00:00 0\n"
+        );
+    }
+
+    #[test]
+    fn envelope_wraps_the_events() {
+        let printer = printer(Output::JsonArray);
+        let envelope = json!({"query": {"logGroupName": "group"}, "region": "eu-west-1"});
+        let mut out = Vec::new();
+        printer.write_header(&mut out, Some(&envelope)).unwrap();
+        printer.print(&mut out, &event()).unwrap();
+        printer.finish(&mut out, Some(false)).unwrap();
+
+        let value: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value["query"], envelope["query"]);
+        assert_eq!(value["region"], "eu-west-1");
+        assert_eq!(value["events"][0]["eventId"], "1");
+        assert_eq!(value["count"], 1);
+        assert_eq!(value["truncated"], false);
+    }
+
+    #[test]
+    fn strips_control_characters() {
+        let printer = Printer {
+            strip: Some(Regex::new(CONTROL).unwrap()),
+            ..printer(Output::Raw)
+        };
+        let event = LogEvent {
+            message: Some("\x1b[1;31mred\x1b[0m\tbell\x07 \u{fffd}\r".to_string()),
+            ..event()
+        };
+        let mut out = Vec::new();
+        printer.print(&mut out, &event).unwrap();
+        printer.finish(&mut out, None).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "red\tbell \u{fffd}\n");
+    }
+
+    #[test]
+    fn unique_collapses_repeated_messages() {
+        let print = |output, global| {
+            let printer = Printer {
+                unique: Some(Unique::new(global)),
+                ..printer(output)
+            };
+            let mut out = Vec::new();
+            for message in &["a", "a", "b", "a"] {
+                let event = LogEvent {
+                    message: Some(message.to_string()),
+                    ..event()
+                };
+                printer.print(&mut out, &event).unwrap();
+            }
+            printer.finish(&mut out, None).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            print(Output::Raw, false),
+            "      2 a\n      1 b\n      1 a\n"
+        );
+        assert_eq!(print(Output::Raw, true), "      3 a\n      1 b\n");
+
+        let json = print(Output::Json, true);
+        let lines: Vec<Value> = json
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        assert_eq!(lines[0]["message"], "a");
+        assert_eq!(lines[0]["count"], 3);
+        assert_eq!(lines[1]["count"], 1);
+        assert!(json.ends_with(",\"count\":1}\n"));
+    }
+
+    #[test]
+    fn excludes_streams() {
+        let printer = Printer {
+            exclude_stream: Some(Regex::new("^health").unwrap()),
+            ..printer(Output::Raw)
+        };
+        let mut out = Vec::new();
+        for (stream, message) in &[("app", "kept"), ("health-check", "dropped")] {
+            let event = LogEvent {
+                log_stream_name: Some(stream.to_string()),
+                message: Some(message.to_string()),
+                ..event()
+            };
+            printer.print(&mut out, &event).unwrap();
+        }
+        assert!(!printer.is_passthrough());
+        assert_eq!(String::from_utf8(out).unwrap(), "kept\n");
+    }
+
+    #[test]
+    fn rotates_output_files() {
+        let dir = env::temp_dir().join(format!("cloudwatch-rotate-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.log");
+        let mut file = OutputFile::create(path.clone(), Some(4)).unwrap();
+        for line in &["one", "two", "three"] {
+            // Written in pieces, which must stay in the same file
+            file.write_all(line.as_bytes()).unwrap();
+            file.write_all(NEWLINE).unwrap();
+        }
+        file.flush().unwrap();
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("out.log"), "three\n");
+        assert_eq!(read("out.log.1"), "two\n");
+        assert_eq!(read("out.log.2"), "one\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn filters_by_time() {
+        let filter = EventFilter {
+            grep: None,
+            invert: false,
+            json: Vec::new(),
+            after: Some(1_500_000_000_000),
+            before: Some(1_500_000_001_000),
+        };
+        let at = |x| LogEvent {
+            timestamp: Some(x),
+            ..event()
+        };
+        assert!(!filter.is_empty());
+        assert!(!filter.matches(&at(1_499_999_999_999)));
+        assert!(filter.matches(&at(1_500_000_000_000)));
+        assert!(filter.matches(&at(1_500_000_001_000)));
+        assert!(!filter.matches(&at(1_500_000_001_001)));
+    }
+
+    #[test]
+    fn parses_filter_patterns() {
+        let compare = |field: &str, operator: &str, value: &str| {
+            Condition::Compare(field.to_string(), operator.to_string(), value.to_string())
+        };
+        assert_eq!(
+            FilterPattern::parse(r#"ERROR "out of memory" ?WARN -DEBUG"#),
+            Ok(FilterPattern::Terms {
+                required: vec!["ERROR".to_string(), "\"out of memory\"".to_string()],
+                optional: vec!["WARN".to_string()],
+                excluded: vec!["DEBUG".to_string()],
+            })
+        );
+        assert_eq!(
+            FilterPattern::parse(r#"{ $.level = "ERROR" && ($.ms > 100 || $.user NOT EXISTS) }"#),
+            Ok(FilterPattern::Json(Condition::All(vec![
+                compare("$.level", "=", "\"ERROR\""),
+                Condition::Any(vec![
+                    compare("$.ms", ">", "100"),
+                    compare("$.user", "NOT", "EXISTS"),
+                ]),
+            ])))
+        );
+        assert_eq!(
+            FilterPattern::parse("[ip, ..., status = 4*, bytes]"),
+            Ok(FilterPattern::SpaceDelimited(
+                vec![
+                    "ip".to_string(),
+                    "...".to_string(),
+                    "status".to_string(),
+                    "bytes".to_string()
+                ],
+                vec![compare("status", "=", "4*")],
+            ))
+        );
+        for pattern in &[
+            r#"{ $.level == "ERROR" }"#,
+            r#"{ $.level = "ERROR" "#,
+            r#"{ level = "ERROR" }"#,
+            "{ $.a = 1 & $.b = 2 }",
+            "[a, b = ]",
+            r#"say "hi"#,
+        ] {
+            assert!(FilterPattern::parse(pattern).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn query_results_table() {
+        let row = |fields: &[(&str, &str)]| -> QueryRow {
+            fields
+                .iter()
+                .map(|(x, y)| (x.to_string(), y.to_string()))
+                .collect()
+        };
+        let rows = vec![
+            row(&[("status", "200"), ("count(*)", "1500")]),
+            row(&[
+                ("status", "503"),
+                ("count(*)", "7"),
+                ("@message", "unavailable"),
+            ]),
+        ];
+        let mut out = Vec::new();
+        print_table(&mut out, &rows).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "status  count(*)  @message\n\
+             200     1500\n\
+             503     7         unavailable\n"
+        );
+    }
+
+    #[test]
+    fn csv_and_raw_output() {
+        assert_eq!(
+            render(&event(), Output::Csv, &options()),
+            "\"2017-07-14T02:40:00.000+00:00\",\"stream\",\"hello, \"\"world\"\"\"\n"
+        );
+        assert_eq!(
+            render(&event(), Output::Raw, &options()),
+            "hello, \"world\"\n"
+        );
+    }
+
+    #[test]
+    fn csv_options() {
+        let options = TextOptions {
+            fields: Some(vec!["eventId".to_string(), "message".to_string()]),
+            csv: Csv {
+                delimiter: ';',
+                header: true,
+                bom: true,
+            },
+            ..options()
+        };
+        let mut writer = Output::Csv.writer();
+        let mut out = Vec::new();
+        writer.write_header(&mut out, &options, None).unwrap();
+        writer.write_event(&mut out, &event(), &options).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\u{feff}eventId;message\n\"1\";\"hello, \"\"world\"\"\"\n"
+        );
+
+        let options = TextOptions {
+            csv: Csv {
+                delimiter: '\t',
+                header: false,
+                bom: false,
+            },
+            ..options
+        };
+        let mut out = Vec::new();
+        Output::Csv
+            .writer()
+            .write_header(&mut out, &options, None)
+            .unwrap();
+        assert!(out.is_empty());
+        assert_eq!(
+            render(&event(), Output::Csv, &options),
+            "\"1\"\t\"hello, \"\"world\"\"\"\n"
+        );
+    }
 }